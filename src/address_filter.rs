@@ -1,25 +1,84 @@
-use serde::{Deserialize};
+use crate::error::{FilterError, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 
-use globset::Glob;
-
-#[derive(Debug, Deserialize)]
+/// A set of glob patterns matched against addresses. The patterns are
+/// compiled once, when the filter is built, so a malformed pattern is
+/// reported up front instead of failing on the first message.
+///
+/// A pattern prefixed with `!` excludes: an address matches when it hits a
+/// plain pattern (or there are only `!` patterns) and no `!` pattern, so
+/// `["*@tatari.tv", "!bots@tatari.tv"]` is every tatari.tv address but one.
+#[derive(Debug, Default, Clone)]
 pub struct AddressFilter {
     pub patterns: Vec<String>,
+    matcher: GlobSet,
+    excluded: GlobSet,
+}
+
+fn build_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            Glob::new(pattern).map_err(|e| {
+                FilterError::Config(format!("Invalid pattern '{}': {}", pattern, e))
+            })?,
+        );
+    }
+    builder
+        .build()
+        .map_err(|e| FilterError::Config(format!("Invalid patterns {:?}: {}", patterns, e)))
 }
 
 impl AddressFilter {
+    pub fn new(patterns: Vec<String>) -> Result<Self> {
+        Self::compile(patterns, str::to_string)
+    }
+
+    /// The same patterns, each also matching anywhere inside the value as if
+    /// wrapped in `*...*`. `patterns` keeps them as written.
+    pub fn unanchored(&self) -> Result<Self> {
+        Self::compile(self.patterns.clone(), unanchor)
+    }
+
+    fn compile(patterns: Vec<String>, rewrite: impl Fn(&str) -> String) -> Result<Self> {
+        let mut included = Vec::new();
+        let mut excluded = Vec::new();
+        for pattern in &patterns {
+            match pattern.strip_prefix('!') {
+                Some(negated) => excluded.push(rewrite(negated)),
+                None => included.push(rewrite(pattern)),
+            }
+        }
+        Ok(Self {
+            matcher: build_set(&included)?,
+            excluded: build_set(&excluded)?,
+            patterns,
+        })
+    }
+
     pub fn matches(&self, emails: &[String]) -> bool {
-        self.patterns.iter().any(|pattern| {
-            let glob = Glob::new(pattern).expect("Invalid glob pattern").compile_matcher();
-            emails.iter().any(|email| glob.is_match(email))
+        emails.iter().any(|email| {
+            let included = if self.matcher.is_empty() {
+                !self.excluded.is_empty()
+            } else {
+                self.matcher.is_match(email)
+            };
+            included && !self.excluded.is_match(email)
         })
     }
 }
 
+/// Wraps a glob in `*` on whichever ends don't already have one, so it
+/// matches a substring instead of the whole string.
+pub(crate) fn unanchor(pattern: &str) -> String {
+    let start = if pattern.starts_with('*') { "" } else { "*" };
+    let end = if pattern.ends_with('*') { "" } else { "*" };
+    format!("{}{}{}", start, pattern, end)
+}
+
 #[cfg(test)]
 mod tests {
     use super::AddressFilter;
-    use std::collections::HashSet;
 
     fn test_emails() -> Vec<String> {
         vec![
@@ -33,18 +92,79 @@ mod tests {
 
     #[test]
     fn test_address_filter_single_match() {
-        let filter = AddressFilter {
-            patterns: vec!["*@tatari.tv".to_string()],
-        };
+        let filter = AddressFilter::new(vec!["*@tatari.tv".to_string()]).unwrap();
         let emails = test_emails();
 
         let expected_matches = vec!["scott.idler@tatari.tv", "admin@tatari.tv"];
         let actual_matches: Vec<_> = emails
             .iter()
-            .filter(|email| filter.matches(&vec![email.to_string()]))
+            .filter(|email| filter.matches(&[email.to_string()]))
             .collect();
 
         assert_eq!(actual_matches, expected_matches);
     }
-}
 
+    #[test]
+    fn test_address_filter_non_match() {
+        let filter =
+            AddressFilter::new(vec!["*@tatari.tv".to_string(), "boss@*".to_string()]).unwrap();
+        assert!(!filter.matches(&["user@example.com".to_string()]));
+        assert!(!AddressFilter::default().matches(&["user@example.com".to_string()]));
+    }
+
+    #[test]
+    fn test_anchored_vs_unanchored() {
+        let anchored = AddressFilter::new(vec!["tatari.tv".to_string()]).unwrap();
+        assert!(
+            !anchored.matches(&["scott@tatari.tv".to_string()]),
+            "a bare pattern is a whole-address match"
+        );
+
+        let unanchored = anchored.unanchored().unwrap();
+        assert!(unanchored.matches(&["scott@tatari.tv".to_string()]));
+        assert!(!unanchored.matches(&["scott@example.com".to_string()]));
+        assert_eq!(unanchored.patterns, vec!["tatari.tv"]);
+
+        let wildcarded = AddressFilter::new(vec!["*@tatari.tv".to_string()])
+            .unwrap()
+            .unanchored()
+            .unwrap();
+        assert!(wildcarded.matches(&["scott@tatari.tv".to_string()]));
+    }
+
+    #[test]
+    fn test_negated_patterns() {
+        let filter = AddressFilter::new(vec![
+            "*@tatari.tv".to_string(),
+            "!bots@tatari.tv".to_string(),
+        ])
+        .unwrap();
+        assert!(filter.matches(&["scott@tatari.tv".to_string()]));
+        assert!(!filter.matches(&["bots@tatari.tv".to_string()]));
+        assert!(
+            !filter.matches(&["user@example.com".to_string()]),
+            "still needs a positive match"
+        );
+        assert!(
+            filter.matches(&["bots@tatari.tv".to_string(), "admin@tatari.tv".to_string()]),
+            "any one address may match"
+        );
+
+        let only_negated = AddressFilter::new(vec!["!*@spam.example".to_string()]).unwrap();
+        assert!(only_negated.matches(&["user@example.com".to_string()]));
+        assert!(!only_negated.matches(&["promo@spam.example".to_string()]));
+
+        let unanchored = AddressFilter::new(vec!["tatari.tv".to_string(), "!bots".to_string()])
+            .unwrap()
+            .unanchored()
+            .unwrap();
+        assert!(unanchored.matches(&["scott@tatari.tv".to_string()]));
+        assert!(!unanchored.matches(&["bots@tatari.tv".to_string()]));
+    }
+
+    #[test]
+    fn test_address_filter_malformed_pattern_is_an_error() {
+        let err = AddressFilter::new(vec!["[unclosed@tatari.tv".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("[unclosed@tatari.tv"), "{}", err);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::error::{FilterError, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::message::Message;
+
+/// One line of the audit log: an action taken (or attempted) on one message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    /// RFC 3339, UTC.
+    pub timestamp: String,
+    pub uid: u32,
+    pub message_id: Option<String>,
+    pub subject: String,
+    /// `move:<folder>`, `append:<folder>` or `star`.
+    pub action: String,
+    /// `ok`, `failed` or `skipped`.
+    pub result: String,
+}
+
+impl AuditEntry {
+    pub fn new(msg: &Message, action: &str, result: &str, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            timestamp: timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+            uid: msg.uid,
+            message_id: msg.message_id.clone(),
+            subject: msg.subject.clone(),
+            action: action.to_string(),
+            result: result.to_string(),
+        }
+    }
+}
+
+/// Append-only JSON Lines record of every action, kept across runs. Each
+/// entry is flushed as it's written so a crash loses at most the line in hand.
+pub struct AuditLog {
+    out: Box<dyn Write>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                FilterError::Io(format!(
+                    "Failed to open audit log {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        Ok(Self::new(file))
+    }
+
+    pub fn new(out: impl Write + 'static) -> Self {
+        Self { out: Box::new(out) }
+    }
+
+    pub fn write(&mut self, entry: &AuditEntry) -> Result<()> {
+        serde_json::to_writer(&mut self.out, entry)
+            .map_err(|e| FilterError::Io(format!("Failed to write audit entry: {}", e)))?;
+        writeln!(self.out)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_audit_log_appends_json_lines() {
+        let path =
+            std::env::temp_dir().join(format!("imap-filter-audit-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let msg = Message {
+            uid: 7,
+            message_id: Some("<a@example.com>".to_string()),
+            subject: "hi".to_string(),
+            ..Default::default()
+        };
+        let at = DateTime::parse_from_rfc3339("2025-03-07T09:30:00Z")
+            .unwrap()
+            .to_utc();
+
+        let mut log = AuditLog::open(&path).unwrap();
+        log.write(&AuditEntry::new(&msg, "star", "ok", at)).unwrap();
+        log.write(&AuditEntry::new(&msg, "move:Archive", "failed", at))
+            .unwrap();
+        drop(log);
+        AuditLog::open(&path)
+            .unwrap()
+            .write(&AuditEntry::new(&msg, "move:Archive", "ok", at))
+            .unwrap();
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let actions: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line["action"].as_str().unwrap(),
+                    line["result"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                ("star", "ok"),
+                ("move:Archive", "failed"),
+                ("move:Archive", "ok")
+            ]
+        );
+        assert_eq!(lines[0]["timestamp"], "2025-03-07T09:30:00Z");
+        assert_eq!(lines[0]["uid"], 7);
+        assert_eq!(lines[0]["message_id"], "<a@example.com>");
+        assert_eq!(lines[0]["subject"], "hi");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,679 @@
+use crate::error::Result;
+use chrono::{DateTime, FixedOffset};
+use imap::types::{Flag, Mailbox, NameAttribute};
+use imap::Session;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+use crate::labels::{parse_labels, quote_label};
+
+/// An owned copy of the parts of a FETCH response the filter uses.
+#[derive(Debug, Clone, Default)]
+pub struct FetchedMessage {
+    pub seq: u32,
+    pub uid: Option<u32>,
+    pub body: Option<Vec<u8>>,
+    pub internal_date: Option<DateTime<FixedOffset>>,
+    /// RFC822.SIZE in bytes.
+    pub size: Option<u32>,
+    pub flags: Vec<String>,
+    /// Gmail's X-GM-THRID, when fetched via `uid_fetch_thread_ids`.
+    pub thread_id: Option<u64>,
+    /// Gmail's X-GM-LABELS, when fetched via `uid_fetch_labels`.
+    pub labels: Vec<String>,
+}
+
+/// An owned LIST response entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxName {
+    pub name: String,
+    pub delimiter: Option<String>,
+    pub attributes: Vec<String>,
+}
+
+/// The IMAP operations `IMAPFilter` relies on. Implemented for a real
+/// `imap::Session` and, in tests, for an in-memory mock.
+pub trait ImapOps {
+    /// The advertised capabilities out of `KNOWN_CAPABILITIES`.
+    fn capabilities(&mut self) -> Result<HashSet<String>>;
+    fn select(&mut self, mailbox: &str) -> Result<Mailbox>;
+    fn search(&mut self, query: &str) -> Result<HashSet<u32>>;
+    fn uid_search(&mut self, query: &str) -> Result<HashSet<u32>>;
+    fn fetch(&mut self, sequence_set: &str, query: &str) -> Result<Vec<FetchedMessage>>;
+    fn uid_fetch(&mut self, uid_set: &str, query: &str) -> Result<Vec<FetchedMessage>>;
+    /// Gmail thread ids (X-GM-THRID) keyed by UID.
+    fn uid_fetch_thread_ids(&mut self, uid_set: &str) -> Result<HashMap<u32, u64>>;
+    /// Gmail labels (X-GM-LABELS) keyed by UID.
+    fn uid_fetch_labels(&mut self, uid_set: &str) -> Result<HashMap<u32, Vec<String>>>;
+    fn uid_store(&mut self, uid_set: &str, query: &str) -> Result<()>;
+    fn uid_mv(&mut self, uid_set: &str, mailbox: &str) -> Result<()>;
+    fn uid_copy(&mut self, uid_set: &str, mailbox: &str) -> Result<()>;
+    fn expunge(&mut self) -> Result<()>;
+    fn uid_expunge(&mut self, uid_set: &str) -> Result<()>;
+    fn list(&mut self, reference: Option<&str>, pattern: Option<&str>) -> Result<Vec<MailboxName>>;
+    fn create(&mut self, mailbox: &str) -> Result<()>;
+    /// APPENDs `content` to `mailbox` with the given flags and INTERNALDATE.
+    fn append(
+        &mut self,
+        mailbox: &str,
+        content: &[u8],
+        flags: &[String],
+        internal_date: Option<DateTime<FixedOffset>>,
+    ) -> Result<()>;
+    fn logout(&mut self) -> Result<()>;
+}
+
+fn to_fetched(fetch: &imap::types::Fetch) -> FetchedMessage {
+    FetchedMessage {
+        seq: fetch.message,
+        uid: fetch.uid,
+        body: fetch.body().map(|body| body.to_vec()),
+        internal_date: fetch.internal_date(),
+        size: fetch.size,
+        flags: fetch.flags().iter().map(|flag| flag.to_string()).collect(),
+        thread_id: None,
+        labels: Vec::new(),
+    }
+}
+
+/// The number following `key ` in a FETCH response line.
+fn fetch_item<T: std::str::FromStr>(line: &str, key: &str) -> Option<T> {
+    let start = line.find(key)? + key.len();
+    let digits: String = line[start..]
+        .trim_start()
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+/// Parses `* 3 FETCH (X-GM-THRID 1266894439832287888 UID 41)` lines, which
+/// the imap crate's FETCH parser doesn't understand, into UID → thread id.
+pub fn parse_thread_ids(response: &str) -> HashMap<u32, u64> {
+    response
+        .lines()
+        .filter(|line| line.starts_with('*') && line.contains(" FETCH "))
+        .filter_map(|line| Some((fetch_item(line, "UID ")?, fetch_item(line, "X-GM-THRID ")?)))
+        .collect()
+}
+
+/// The parenthesized list following `key ` in a FETCH response line,
+/// parentheses included; parentheses inside quoted strings are skipped.
+fn fetch_list<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(key)? + key.len();
+    let rest = line[start..].trim_start();
+    if !rest.starts_with('(') {
+        return None;
+    }
+    let mut depth = 0usize;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (index, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&rest[..=index]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses `* 3 FETCH (X-GM-LABELS (\Starred "Team Updates") UID 41)` lines,
+/// which the imap crate's FETCH parser doesn't understand, into UID → labels.
+pub fn parse_fetch_labels(response: &str) -> HashMap<u32, Vec<String>> {
+    response
+        .lines()
+        .filter(|line| line.starts_with('*') && line.contains(" FETCH "))
+        .filter_map(|line| {
+            Some((
+                fetch_item(line, "UID ")?,
+                parse_labels(fetch_list(line, "X-GM-LABELS ")?),
+            ))
+        })
+        .collect()
+}
+
+fn attribute_name(attribute: &NameAttribute<'_>) -> String {
+    match attribute {
+        NameAttribute::NoInferiors => "\\Noinferiors".to_string(),
+        NameAttribute::NoSelect => "\\Noselect".to_string(),
+        NameAttribute::Marked => "\\Marked".to_string(),
+        NameAttribute::Unmarked => "\\Unmarked".to_string(),
+        NameAttribute::Custom(name) => name.to_string(),
+    }
+}
+
+/// The capabilities the filter changes behavior on. The imap crate's
+/// `Capability` type comes from an imap-proto version we can't name, so
+/// capabilities are probed by name instead of enumerated.
+pub const KNOWN_CAPABILITIES: &[&str] =
+    &["IMAP4REV1", "MOVE", "UIDPLUS", "SPECIAL-USE", "X-GM-EXT-1"];
+
+impl<T: Read + Write> ImapOps for Session<T> {
+    fn capabilities(&mut self) -> Result<HashSet<String>> {
+        let capabilities = Session::capabilities(self)?;
+        Ok(KNOWN_CAPABILITIES
+            .iter()
+            .filter(|name| capabilities.has_str(name))
+            .map(|name| name.to_string())
+            .collect())
+    }
+
+    fn select(&mut self, mailbox: &str) -> Result<Mailbox> {
+        Ok(Session::select(self, mailbox)?)
+    }
+
+    fn search(&mut self, query: &str) -> Result<HashSet<u32>> {
+        Ok(Session::search(self, query)?)
+    }
+
+    fn uid_search(&mut self, query: &str) -> Result<HashSet<u32>> {
+        Ok(Session::uid_search(self, query)?)
+    }
+
+    fn fetch(&mut self, sequence_set: &str, query: &str) -> Result<Vec<FetchedMessage>> {
+        Ok(Session::fetch(self, sequence_set, query)?
+            .iter()
+            .map(to_fetched)
+            .collect())
+    }
+
+    fn uid_fetch(&mut self, uid_set: &str, query: &str) -> Result<Vec<FetchedMessage>> {
+        Ok(Session::uid_fetch(self, uid_set, query)?
+            .iter()
+            .map(to_fetched)
+            .collect())
+    }
+
+    fn uid_fetch_thread_ids(&mut self, uid_set: &str) -> Result<HashMap<u32, u64>> {
+        let response =
+            self.run_command_and_read_response(format!("UID FETCH {} (UID X-GM-THRID)", uid_set))?;
+        Ok(parse_thread_ids(&String::from_utf8_lossy(&response)))
+    }
+
+    fn uid_fetch_labels(&mut self, uid_set: &str) -> Result<HashMap<u32, Vec<String>>> {
+        let response =
+            self.run_command_and_read_response(format!("UID FETCH {} (UID X-GM-LABELS)", uid_set))?;
+        Ok(parse_fetch_labels(&String::from_utf8_lossy(&response)))
+    }
+
+    fn uid_store(&mut self, uid_set: &str, query: &str) -> Result<()> {
+        Session::uid_store(self, uid_set, query)?;
+        Ok(())
+    }
+
+    fn uid_mv(&mut self, uid_set: &str, mailbox: &str) -> Result<()> {
+        Ok(Session::uid_mv(self, uid_set, mailbox)?)
+    }
+
+    fn uid_copy(&mut self, uid_set: &str, mailbox: &str) -> Result<()> {
+        // Unlike uid_mv, the imap crate passes the COPY mailbox through unquoted
+        Ok(Session::uid_copy(self, uid_set, quote_label(mailbox))?)
+    }
+
+    fn expunge(&mut self) -> Result<()> {
+        Session::expunge(self)?;
+        Ok(())
+    }
+
+    fn uid_expunge(&mut self, uid_set: &str) -> Result<()> {
+        Session::uid_expunge(self, uid_set)?;
+        Ok(())
+    }
+
+    fn list(&mut self, reference: Option<&str>, pattern: Option<&str>) -> Result<Vec<MailboxName>> {
+        Ok(Session::list(self, reference, pattern)?
+            .iter()
+            .map(|name| MailboxName {
+                name: name.name().to_string(),
+                delimiter: name.delimiter().map(str::to_string),
+                attributes: name.attributes().iter().map(attribute_name).collect(),
+            })
+            .collect())
+    }
+
+    fn create(&mut self, mailbox: &str) -> Result<()> {
+        Ok(Session::create(self, mailbox)?)
+    }
+
+    fn append(
+        &mut self,
+        mailbox: &str,
+        content: &[u8],
+        flags: &[String],
+        internal_date: Option<DateTime<FixedOffset>>,
+    ) -> Result<()> {
+        let flags: Vec<Flag> = flags.iter().map(|flag| Flag::from(flag.as_str())).collect();
+        Ok(Session::append_with_flags_and_date(
+            self,
+            mailbox,
+            content,
+            &flags,
+            internal_date,
+        )?)
+    }
+
+    fn logout(&mut self) -> Result<()> {
+        Ok(Session::logout(self)?)
+    }
+}
+
+/// Moves messages on a server without MOVE: copy them to `mailbox`, flag
+/// the originals `\Deleted`, then expunge. With UIDPLUS only the given UIDs
+/// are expunged; otherwise a plain EXPUNGE also removes any other message
+/// already flagged `\Deleted` in the mailbox.
+pub fn uid_move_copy<C: ImapOps>(
+    client: &mut C,
+    uid_set: &str,
+    mailbox: &str,
+    uidplus: bool,
+) -> Result<()> {
+    client.uid_copy(uid_set, mailbox)?;
+    client.uid_store(uid_set, "+FLAGS (\\Deleted)")?;
+    expunge_deleted(client, uid_set, uidplus)
+}
+
+/// Expunges messages already flagged `\Deleted`: just `uid_set` with
+/// UIDPLUS, every such message in the mailbox without it.
+pub fn expunge_deleted<C: ImapOps>(client: &mut C, uid_set: &str, uidplus: bool) -> Result<()> {
+    if uidplus {
+        client.uid_expunge(uid_set)
+    } else {
+        client.expunge()
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    fn parse_set(set: &str) -> Vec<u32> {
+        set.split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect()
+    }
+
+    /// An in-memory IMAP server holding a single INBOX. Every call is
+    /// recorded in `calls` as a short command string for assertions.
+    #[derive(Debug, Default)]
+    pub struct MockClient {
+        pub inbox: Vec<FetchedMessage>,
+        pub mailboxes: Vec<MailboxName>,
+        /// Hierarchy delimiter reported by `LIST "" ""`; `/` when unset.
+        pub delimiter: Option<String>,
+        /// Advertised capabilities; a Gmail-like set when unset.
+        pub capabilities: Option<Vec<String>>,
+        /// Calls starting with any of these prefixes fail after being recorded.
+        pub failing: Vec<String>,
+        /// Calls starting with any of these prefixes fail as a dropped connection.
+        pub disconnecting: Vec<String>,
+        /// Sequence numbers SEARCH still reports but FETCH no longer returns,
+        /// as if expunged in between.
+        pub vanished: Vec<u32>,
+        pub calls: Vec<String>,
+    }
+
+    impl MockClient {
+        pub fn new(inbox: Vec<FetchedMessage>) -> Self {
+            Self {
+                inbox,
+                ..Default::default()
+            }
+        }
+
+        /// Adds a message with sequence number and UID derived from its position.
+        pub fn with_message(mut self, uid: u32, raw: &str) -> Self {
+            self.inbox.push(FetchedMessage {
+                seq: self.inbox.len() as u32 + 1,
+                uid: Some(uid),
+                body: Some(raw.as_bytes().to_vec()),
+                ..Default::default()
+            });
+            self
+        }
+
+        /// Adds mailboxes to the LIST response, using `/` as the delimiter.
+        pub fn with_mailboxes(mut self, names: &[&str]) -> Self {
+            self.mailboxes.extend(names.iter().map(|name| MailboxName {
+                name: name.to_string(),
+                delimiter: Some("/".to_string()),
+                ..Default::default()
+            }));
+            self
+        }
+
+        pub fn with_delimiter(mut self, delimiter: &str) -> Self {
+            self.delimiter = Some(delimiter.to_string());
+            self
+        }
+
+        pub fn with_capabilities(mut self, capabilities: &[&str]) -> Self {
+            self.capabilities = Some(capabilities.iter().map(|name| name.to_string()).collect());
+            self
+        }
+
+        pub fn with_failure(mut self, prefix: &str) -> Self {
+            self.failing.push(prefix.to_string());
+            self
+        }
+
+        pub fn with_disconnect(mut self, prefix: &str) -> Self {
+            self.disconnecting.push(prefix.to_string());
+            self
+        }
+
+        pub fn with_vanished(mut self, seq: u32) -> Self {
+            self.vanished.push(seq);
+            self
+        }
+
+        fn record(&mut self, call: String) -> Result<()> {
+            let fails = self
+                .failing
+                .iter()
+                .any(|prefix| call.starts_with(prefix.as_str()));
+            let disconnects = self
+                .disconnecting
+                .iter()
+                .any(|prefix| call.starts_with(prefix.as_str()));
+            self.calls.push(call);
+            if disconnects {
+                return Err(imap::Error::ConnectionLost.into());
+            }
+            if fails {
+                return Err(imap::Error::Bad(format!(
+                    "mock failure: {}",
+                    self.calls[self.calls.len() - 1]
+                ))
+                .into());
+            }
+            Ok(())
+        }
+
+        /// Recorded calls that start with `prefix`.
+        pub fn calls_to(&self, prefix: &str) -> Vec<&str> {
+            self.calls
+                .iter()
+                .filter(|call| call.starts_with(prefix))
+                .map(String::as_str)
+                .collect()
+        }
+    }
+
+    impl ImapOps for MockClient {
+        fn capabilities(&mut self) -> Result<HashSet<String>> {
+            self.record("CAPABILITY".to_string())?;
+            Ok(match &self.capabilities {
+                Some(capabilities) => capabilities.iter().cloned().collect(),
+                None => ["IMAP4REV1", "X-GM-EXT-1", "MOVE"]
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            })
+        }
+
+        fn select(&mut self, mailbox: &str) -> Result<Mailbox> {
+            self.record(format!("SELECT {}", mailbox))?;
+            Ok(Mailbox {
+                exists: self.inbox.len() as u32,
+                ..Default::default()
+            })
+        }
+
+        fn search(&mut self, query: &str) -> Result<HashSet<u32>> {
+            self.record(format!("SEARCH {}", query))?;
+            Ok(self
+                .inbox
+                .iter()
+                .map(|msg| msg.seq)
+                .chain(self.vanished.iter().copied())
+                .collect())
+        }
+
+        /// `X-GM-LABELS <label>` searches by stored labels, `X-GM-THRID <id>`
+        /// by thread and `FLAGGED` by flags; anything else matches every message.
+        fn uid_search(&mut self, query: &str) -> Result<HashSet<u32>> {
+            self.record(format!("UID SEARCH {}", query))?;
+            let label = query.strip_prefix("X-GM-LABELS ").map(parse_labels);
+            let thread: Option<u64> = query
+                .strip_prefix("X-GM-THRID ")
+                .and_then(|id| id.parse().ok());
+            let flagged = query == "FLAGGED";
+            Ok(self
+                .inbox
+                .iter()
+                .filter(|msg| {
+                    label
+                        .as_ref()
+                        .is_none_or(|labels| labels.iter().all(|label| msg.labels.contains(label)))
+                })
+                .filter(|msg| thread.is_none_or(|thread| msg.thread_id == Some(thread)))
+                .filter(|msg| !flagged || msg.flags.iter().any(|flag| flag == "\\Flagged"))
+                .filter_map(|msg| msg.uid)
+                .collect())
+        }
+
+        fn fetch(&mut self, sequence_set: &str, query: &str) -> Result<Vec<FetchedMessage>> {
+            self.record(format!("FETCH {} {}", sequence_set, query))?;
+            let wanted = parse_set(sequence_set);
+            Ok(self
+                .inbox
+                .iter()
+                .filter(|msg| wanted.contains(&msg.seq))
+                .cloned()
+                .collect())
+        }
+
+        fn uid_fetch(&mut self, uid_set: &str, query: &str) -> Result<Vec<FetchedMessage>> {
+            self.record(format!("UID FETCH {} {}", uid_set, query))?;
+            let wanted = parse_set(uid_set);
+            Ok(self
+                .inbox
+                .iter()
+                .filter(|msg| msg.uid.is_some_and(|uid| wanted.contains(&uid)))
+                .cloned()
+                .collect())
+        }
+
+        fn uid_fetch_thread_ids(&mut self, uid_set: &str) -> Result<HashMap<u32, u64>> {
+            self.record(format!("UID FETCH {} (UID X-GM-THRID)", uid_set))?;
+            let wanted = parse_set(uid_set);
+            Ok(self
+                .inbox
+                .iter()
+                .filter_map(|msg| Some((msg.uid?, msg.thread_id?)))
+                .filter(|(uid, _)| wanted.contains(uid))
+                .collect())
+        }
+
+        fn uid_fetch_labels(&mut self, uid_set: &str) -> Result<HashMap<u32, Vec<String>>> {
+            self.record(format!("UID FETCH {} (UID X-GM-LABELS)", uid_set))?;
+            let wanted = parse_set(uid_set);
+            Ok(self
+                .inbox
+                .iter()
+                .filter_map(|msg| Some((msg.uid?, msg.labels.clone())))
+                .filter(|(uid, _)| wanted.contains(uid))
+                .collect())
+        }
+
+        /// Applies `+ITEM values` / `-ITEM values` to the stored labels
+        /// (for X-GM-LABELS) or flags, which read back like a real server's:
+        /// labels only through `uid_fetch_labels`, flags through FETCH.
+        fn uid_store(&mut self, uid_set: &str, query: &str) -> Result<()> {
+            self.record(format!("UID STORE {} {}", uid_set, query))?;
+            let (item, list) = query.split_once(' ').unwrap_or((query, ""));
+            let labels = parse_labels(list);
+            let wanted = parse_set(uid_set);
+            for msg in self
+                .inbox
+                .iter_mut()
+                .filter(|msg| msg.uid.is_some_and(|uid| wanted.contains(&uid)))
+            {
+                let stored = if item.ends_with("X-GM-LABELS") {
+                    &mut msg.labels
+                } else {
+                    &mut msg.flags
+                };
+                if item.starts_with('-') {
+                    stored.retain(|value| !labels.contains(value));
+                } else {
+                    for label in &labels {
+                        if !stored.contains(label) {
+                            stored.push(label.clone());
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn uid_mv(&mut self, uid_set: &str, mailbox: &str) -> Result<()> {
+            self.record(format!("UID MOVE {} {}", uid_set, mailbox))?;
+            Ok(())
+        }
+
+        fn uid_copy(&mut self, uid_set: &str, mailbox: &str) -> Result<()> {
+            self.record(format!("UID COPY {} {}", uid_set, mailbox))?;
+            Ok(())
+        }
+
+        fn expunge(&mut self) -> Result<()> {
+            self.record("EXPUNGE".to_string())?;
+            Ok(())
+        }
+
+        fn uid_expunge(&mut self, uid_set: &str) -> Result<()> {
+            self.record(format!("UID EXPUNGE {}", uid_set))?;
+            Ok(())
+        }
+
+        fn list(
+            &mut self,
+            reference: Option<&str>,
+            pattern: Option<&str>,
+        ) -> Result<Vec<MailboxName>> {
+            self.record(format!(
+                "LIST {} {}",
+                reference.unwrap_or(""),
+                pattern.unwrap_or("")
+            ))?;
+            if pattern == Some("") {
+                let delimiter = self.delimiter.clone().unwrap_or_else(|| "/".to_string());
+                return Ok(vec![MailboxName {
+                    delimiter: Some(delimiter),
+                    attributes: vec!["\\Noselect".to_string()],
+                    ..Default::default()
+                }]);
+            }
+            Ok(self.mailboxes.clone())
+        }
+
+        fn create(&mut self, mailbox: &str) -> Result<()> {
+            self.record(format!("CREATE {}", mailbox))?;
+            self.mailboxes.push(MailboxName {
+                name: mailbox.to_string(),
+                ..Default::default()
+            });
+            Ok(())
+        }
+
+        fn append(
+            &mut self,
+            mailbox: &str,
+            content: &[u8],
+            flags: &[String],
+            internal_date: Option<DateTime<FixedOffset>>,
+        ) -> Result<()> {
+            let date = internal_date
+                .map(|date| format!(" \"{}\"", date.format("%d-%h-%Y %T %z")))
+                .unwrap_or_default();
+            self.record(format!(
+                "APPEND {} ({}){} {{{}}}",
+                mailbox,
+                flags.join(" "),
+                date,
+                content.len()
+            ))
+        }
+
+        fn logout(&mut self) -> Result<()> {
+            self.record("LOGOUT".to_string())?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockClient;
+    use super::*;
+
+    #[test]
+    fn test_parse_thread_ids() {
+        let response = "* 3 FETCH (X-GM-THRID 1266894439832287888 UID 41)\r\n\
+                        * 4 FETCH (UID 42 X-GM-THRID 1266894439832287999)\r\n\
+                        * 5 EXISTS\r\n";
+        let thread_ids = parse_thread_ids(response);
+
+        assert_eq!(thread_ids.len(), 2);
+        assert_eq!(thread_ids[&41], 1266894439832287888);
+        assert_eq!(thread_ids[&42], 1266894439832287999);
+    }
+
+    #[test]
+    fn test_parse_fetch_labels() {
+        let response = "* 3 FETCH (X-GM-LABELS (\\Starred \"Team (A)\" Work) UID 41)\r\n\
+                        * 4 FETCH (UID 42 X-GM-LABELS ())\r\n\
+                        * 5 FETCH (UID 43 FLAGS (\\Seen))\r\n";
+        let labels = parse_fetch_labels(response);
+
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[&41], vec!["\\Starred", "Team (A)", "Work"]);
+        assert!(labels[&42].is_empty());
+    }
+
+    #[test]
+    fn test_uid_move_copy_with_uidplus() {
+        let mut client = MockClient::default();
+        uid_move_copy(&mut client, "7", "Archive", true).unwrap();
+        assert_eq!(
+            client.calls,
+            vec![
+                "UID COPY 7 Archive",
+                "UID STORE 7 +FLAGS (\\Deleted)",
+                "UID EXPUNGE 7"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_uid_move_copy_without_uidplus() {
+        let mut client = MockClient::default();
+        uid_move_copy(&mut client, "7", "Archive", false).unwrap();
+        assert_eq!(
+            client.calls,
+            vec![
+                "UID COPY 7 Archive",
+                "UID STORE 7 +FLAGS (\\Deleted)",
+                "EXPUNGE"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_uid_move_copy_failed_copy_leaves_original_alone() {
+        let mut client = MockClient::default().with_failure("UID COPY");
+        assert!(uid_move_copy(&mut client, "7", "Archive", true).is_err());
+        assert!(client.calls_to("UID STORE").is_empty());
+        assert!(client.calls_to("UID EXPUNGE").is_empty());
+    }
+}
@@ -0,0 +1,1021 @@
+use crate::error::{FilterError, Result};
+use chrono::NaiveDate;
+use log::{debug, error, warn};
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::address_filter::AddressFilter;
+use crate::connection::{ConnectionSettings, Security, DEFAULT_TIMEOUT_SECS};
+use crate::credentials::redact;
+use crate::imap_filter::{
+    FilterOptions, FilterStrategy, DEFAULT_FETCH_CHUNK_SIZE, DEFAULT_PROGRESS_INTERVAL,
+    DEFAULT_SPIKE_FACTOR,
+};
+use crate::log_style::LogStyle;
+use crate::message::AgeBasis;
+use crate::message_filter::{deserialize_address_filter, MessageFilter, DEFAULT_FILTER_NAME};
+use crate::query::DEFAULT_INBOX_QUERY;
+
+/// The config schema this build understands. Bump it when a change would
+/// make older configs mean something different.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Name given to the top-level config when it has no `accounts`.
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub version: Option<u32>,
+    pub imap_domain: Option<String>,
+    pub imap_username: Option<String>,
+    pub imap_password: Option<String>,
+    pub imap_password_command: Option<String>,
+    pub imap_password_file: Option<PathBuf>,
+    #[serde(default)]
+    pub security: Security,
+    pub port: Option<u16>,
+    pub connect_timeout_secs: Option<u64>,
+    pub read_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub protocol_debug: bool,
+    /// The username this account must log in as; the run aborts before
+    /// connecting when the resolved username differs.
+    pub confirm_account: Option<String>,
+    #[serde(default)]
+    pub filter_strategy: FilterStrategy,
+    #[serde(default)]
+    pub age_basis: AgeBasis,
+    #[serde(default = "default_true")]
+    pub create_folders: bool,
+    #[serde(default)]
+    pub log_style: LogStyle,
+    /// From-address globs whose messages are never acted on.
+    #[serde(default, deserialize_with = "deserialize_address_filter")]
+    pub never_touch: Option<AddressFilter>,
+    pub inbox_query: Option<String>,
+    /// Gmail search syntax (e.g. `has:attachment larger:5M`) narrowing the fetch.
+    pub gmail_search: Option<String>,
+    /// A Message-ID (e.g. `<abc@example.com>`); only its replies and the rest
+    /// of its thread are fetched, to clean up one conversation.
+    pub references: Option<String>,
+    /// Set from `--since-last-run`; not read from the config file.
+    #[serde(skip)]
+    pub since: Option<NaiveDate>,
+    /// Set from `--max-runtime`; not read from the config file.
+    #[serde(skip)]
+    pub max_runtime: Option<Duration>,
+    /// Set from `--debug-uid`; not read from the config file.
+    #[serde(skip)]
+    pub debug_uid: Option<u32>,
+    #[serde(default)]
+    pub dedupe_by_message_id: bool,
+    #[serde(default)]
+    pub fail_fast: bool,
+    pub limit: Option<usize>,
+    pub progress_interval: Option<usize>,
+    /// Messages per FETCH command; defaults to `DEFAULT_FETCH_CHUNK_SIZE`.
+    pub fetch_chunk_size: Option<usize>,
+    /// JSON Lines file every action is appended to, across runs.
+    pub audit_log: Option<PathBuf>,
+    /// File each run's per-filter action counts are saved to and the next
+    /// run's are compared against, warning on a spike.
+    pub action_counts: Option<PathBuf>,
+    /// How many times its previous count a filter may act before the
+    /// spike warning; defaults to `DEFAULT_SPIKE_FACTOR`.
+    pub spike_factor: Option<f64>,
+    /// Milliseconds to wait between move/star commands; 0 (the default) disables it.
+    #[serde(default)]
+    pub throttle_ms: u64,
+    #[serde(default, deserialize_with = "deserialize_filter_maps")]
+    pub filters: Vec<MessageFilter>,
+    /// Actions (and optionally conditions) applied to messages that no
+    /// filter matched, e.g. `{ move_to: Triage }`. Runs after every filter.
+    #[serde(default, deserialize_with = "deserialize_default_filter")]
+    pub default_filter_action: Option<MessageFilter>,
+    pub folders: Option<HashMap<String, FolderSettings>>,
+    /// Named per-account configs, in the same `- name: {...}` list form as
+    /// filters. When empty the top level is the only account; otherwise
+    /// nothing but `version` may sit beside them, as accounts inherit nothing.
+    #[serde(default, deserialize_with = "deserialize_account_maps")]
+    pub accounts: Vec<(String, Config)>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("version", &self.version)
+            .field("imap_domain", &self.imap_domain)
+            .field("imap_username", &self.imap_username)
+            .field("imap_password", &redact(&self.imap_password))
+            .field(
+                "imap_password_command",
+                &redact(&self.imap_password_command),
+            )
+            .field("imap_password_file", &self.imap_password_file)
+            .field("security", &self.security)
+            .field("port", &self.port)
+            .field("connect_timeout_secs", &self.connect_timeout_secs)
+            .field("read_timeout_secs", &self.read_timeout_secs)
+            .field("protocol_debug", &self.protocol_debug)
+            .field("confirm_account", &self.confirm_account)
+            .field("filter_strategy", &self.filter_strategy)
+            .field("age_basis", &self.age_basis)
+            .field("create_folders", &self.create_folders)
+            .field("log_style", &self.log_style)
+            .field("never_touch", &self.never_touch)
+            .field("inbox_query", &self.inbox_query)
+            .field("gmail_search", &self.gmail_search)
+            .field("references", &self.references)
+            .field("since", &self.since)
+            .field("max_runtime", &self.max_runtime)
+            .field("debug_uid", &self.debug_uid)
+            .field("dedupe_by_message_id", &self.dedupe_by_message_id)
+            .field("fail_fast", &self.fail_fast)
+            .field("limit", &self.limit)
+            .field("progress_interval", &self.progress_interval)
+            .field("fetch_chunk_size", &self.fetch_chunk_size)
+            .field("audit_log", &self.audit_log)
+            .field("action_counts", &self.action_counts)
+            .field("spike_factor", &self.spike_factor)
+            .field("throttle_ms", &self.throttle_ms)
+            .field("filters", &self.filters)
+            .field("default_filter_action", &self.default_filter_action)
+            .field("folders", &self.folders)
+            .field("accounts", &self.accounts)
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FolderSettings {
+    pub read: Option<String>,
+    pub unread: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Config {
+    pub fn filter_options(&self) -> FilterOptions {
+        FilterOptions {
+            strategy: self.filter_strategy,
+            age_basis: self.age_basis,
+            create_folders: self.create_folders,
+            log_style: self.log_style,
+            never_touch: self.never_touch.clone().unwrap_or_default(),
+            inbox_query: self
+                .inbox_query
+                .clone()
+                .unwrap_or_else(|| DEFAULT_INBOX_QUERY.to_string()),
+            gmail_search: self.gmail_search.clone(),
+            references: self.references.clone(),
+            since: self.since,
+            dedupe_by_message_id: self.dedupe_by_message_id,
+            fail_fast: self.fail_fast,
+            limit: self.limit,
+            progress_interval: self.progress_interval.unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+            fetch_chunk_size: self.fetch_chunk_size.unwrap_or(DEFAULT_FETCH_CHUNK_SIZE),
+            throttle: Duration::from_millis(self.throttle_ms),
+            max_runtime: self.max_runtime,
+            debug_uid: self.debug_uid,
+            spike_factor: self.spike_factor.unwrap_or(DEFAULT_SPIKE_FACTOR),
+        }
+    }
+
+    pub fn connection_settings(&self) -> ConnectionSettings {
+        ConnectionSettings {
+            security: self.security,
+            port: self.port,
+            connect_timeout: Duration::from_secs(
+                self.connect_timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            ),
+            read_timeout: Duration::from_secs(
+                self.read_timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            ),
+        }
+    }
+
+    /// Moves `default_filter_action` to the end of `filters`, so it runs
+    /// last and can be picked by name with `--only-filter`.
+    pub fn append_default_filter(&mut self) {
+        self.filters.extend(self.default_filter_action.take());
+    }
+
+    /// Splits the config into the accounts to run: the one named `name`, or
+    /// every account in order. A config without `accounts` is a single
+    /// account called `default`. Duplicate account names are rejected.
+    pub fn into_accounts(mut self, name: Option<&str>) -> Result<Vec<(String, Config)>> {
+        let mut accounts = if self.accounts.is_empty() {
+            vec![(DEFAULT_ACCOUNT.to_string(), self)]
+        } else {
+            std::mem::take(&mut self.accounts)
+        };
+        let mut seen = HashSet::new();
+        if let Some((duplicate, _)) = accounts.iter().find(|(account, _)| !seen.insert(account)) {
+            return Err(FilterError::Config(format!(
+                "Account '{}' is configured more than once",
+                duplicate
+            )));
+        }
+        for (_, config) in &mut accounts {
+            config.append_default_filter();
+        }
+
+        match name {
+            None => Ok(accounts),
+            Some(name) => {
+                let known: Vec<String> = accounts
+                    .iter()
+                    .map(|(account, _)| account.clone())
+                    .collect();
+                accounts
+                    .into_iter()
+                    .find(|(account, _)| account == name)
+                    .map(|account| vec![account])
+                    .ok_or_else(|| {
+                        FilterError::Config(format!(
+                            "Unknown account '{}'; configured accounts: {:?}",
+                            name, known
+                        ))
+                    })
+            }
+        }
+    }
+}
+
+/// The entries of one `{ name: value, ... }` map, in the order written.
+/// A `HashMap` would lose that order when a list item holds several keys.
+struct NamedEntries<T>(Vec<(String, T)>);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for NamedEntries<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for EntriesVisitor<T> {
+            type Value = NamedEntries<T>;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of names to settings")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Self::Value, M::Error> {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(NamedEntries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor(PhantomData))
+    }
+}
+
+/// Flattens the YAML list of `{ name: filter }` maps into named filters,
+/// keeping the order they're written in, since the first match wins.
+fn deserialize_filter_maps<'de, D>(deserializer: D) -> Result<Vec<MessageFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let maps: Vec<NamedEntries<MessageFilter>> = Vec::deserialize(deserializer)?;
+    maps.into_iter()
+        .flat_map(|NamedEntries(entries)| entries)
+        .map(|(name, mut filter)| {
+            filter.name = name;
+            filter.apply_anchoring().map_err(serde::de::Error::custom)
+        })
+        .collect()
+}
+
+fn deserialize_default_filter<'de, D>(deserializer: D) -> Result<Option<MessageFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let filter = MessageFilter {
+        name: DEFAULT_FILTER_NAME.to_string(),
+        catch_all: true,
+        ..MessageFilter::deserialize(deserializer)?
+    };
+    filter
+        .apply_anchoring()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Flattens the YAML list of `{ name: account }` maps, keeping their order.
+fn deserialize_account_maps<'de, D>(deserializer: D) -> Result<Vec<(String, Config)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let maps: Vec<NamedEntries<Config>> = Vec::deserialize(deserializer)?;
+    Ok(maps
+        .into_iter()
+        .flat_map(|NamedEntries(entries)| entries)
+        .collect())
+}
+
+/// Rejects configs written for a newer schema and warns about unversioned ones.
+fn check_version(version: Option<u32>) -> Result<()> {
+    match version {
+        None => {
+            warn!(
+                "Config has no 'version'; assuming version {}",
+                CONFIG_VERSION
+            );
+            Ok(())
+        }
+        Some(version) if version > CONFIG_VERSION => Err(FilterError::Config(format!(
+            "Config version {} is newer than the supported version {}; upgrade imap-filter",
+            version, CONFIG_VERSION
+        ))),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Top-level keys allowed next to `accounts`; nothing else is inherited by
+/// the accounts, so anything else would be silently ignored.
+const ACCOUNTS_SIBLING_KEYS: &[&str] = &["version", "accounts"];
+
+/// Rejects a config that has `accounts` alongside top-level filters or
+/// settings, which would otherwise be dropped without a word.
+fn check_accounts_stand_alone(value: &Value) -> Result<()> {
+    let Value::Mapping(map) = value else {
+        return Ok(());
+    };
+    if !map.contains_key("accounts") {
+        return Ok(());
+    }
+    let ignored: Vec<&str> = map
+        .keys()
+        .filter_map(Value::as_str)
+        .filter(|key| !ACCOUNTS_SIBLING_KEYS.contains(key))
+        .collect();
+    if ignored.is_empty() {
+        Ok(())
+    } else {
+        Err(FilterError::Config(format!(
+            "Top-level {:?} would be ignored next to 'accounts'; move them into each account",
+            ignored
+        )))
+    }
+}
+
+/// Top-level keys whose lists are concatenated across config files rather
+/// than replaced by the later file.
+const CONCATENATED_KEYS: &[&str] = &["filters", "accounts"];
+
+/// Merges `overlay` into `base`: mappings merge key by key, the lists under
+/// `CONCATENATED_KEYS` are appended, and any other value is replaced.
+fn merge_yaml(base: &mut Value, overlay: Value, top_level: bool) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                let concatenate = top_level
+                    && key
+                        .as_str()
+                        .is_some_and(|key| CONCATENATED_KEYS.contains(&key));
+                match (base.get_mut(&key), value) {
+                    (Some(Value::Sequence(existing)), Value::Sequence(more)) if concatenate => {
+                        existing.extend(more)
+                    }
+                    (Some(existing @ Value::Mapping(_)), value @ Value::Mapping(_)) => {
+                        merge_yaml(existing, value, false)
+                    }
+                    (_, value) => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn read_source<R: Read>(path: &Path, stdin: &mut R) -> Result<String> {
+    let mut content = String::new();
+    let result = if path == Path::new("-") {
+        stdin.read_to_string(&mut content).map(|_| content)
+    } else {
+        fs::read_to_string(path)
+    };
+    result.map_err(|e| {
+        error!("Failed to read config file {}: {}", path.display(), e);
+        FilterError::Config(format!(
+            "Failed to read config file {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Keys whose string values may reference environment variables as `${VAR}`
+/// or `${VAR:-default}`: the credentials and the folder a filter moves to,
+/// at any depth (so per-account and per-filter values are covered too).
+const INTERPOLATED_KEYS: &[&str] = &[
+    "imap_domain",
+    "imap_username",
+    "imap_password",
+    "imap_password_command",
+    "imap_password_file",
+    "move_to",
+    "folder",
+    "append_to",
+    "mailbox",
+];
+
+/// Expands `${VAR}` and `${VAR:-default}` in `text` using `lookup`. As in
+/// the shell, `:-` also falls back when the variable is set but empty. An
+/// unset variable without a default is an error.
+fn expand_vars(text: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            FilterError::Config(format!("Unterminated '${{' in config value '{}'", text))
+        })?;
+        let reference = &rest[start + 2..start + end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        let value = lookup(name).filter(|value| default.is_none() || !value.is_empty());
+        match value.or_else(|| default.map(str::to_string)) {
+            Some(value) => expanded.push_str(&value),
+            None => {
+                return Err(FilterError::Config(format!(
+                    "Environment variable '{}' is not set and has no default",
+                    name
+                )))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expands environment references in the values of `INTERPOLATED_KEYS`.
+fn interpolate_env(value: &mut Value, lookup: &impl Fn(&str) -> Option<String>) -> Result<()> {
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map.iter_mut() {
+                let interpolated = key
+                    .as_str()
+                    .is_some_and(|key| INTERPOLATED_KEYS.contains(&key));
+                match value {
+                    Value::String(text) if interpolated => *text = expand_vars(text, lookup)?,
+                    value => interpolate_env(value, lookup)?,
+                }
+            }
+        }
+        Value::Sequence(items) => {
+            for item in items {
+                interpolate_env(item, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The syntax of a config source, picked from its file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// `.json` and `.toml` files are JSON and TOML; anything else, including
+    /// stdin, is YAML (which also accepts JSON).
+    pub fn from_path(path: &Path) -> Self {
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("json") => ConfigFormat::Json,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    /// Parses `content` into a YAML value so every format merges the same way.
+    fn parse(self, content: &str) -> Result<Value> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(content)
+                .map_err(|e| FilterError::Config(format!("Failed to parse YAML: {}", e))),
+            ConfigFormat::Json => {
+                let json: serde_json::Value = serde_json::from_str(content)
+                    .map_err(|e| FilterError::Config(format!("Failed to parse JSON: {}", e)))?;
+                serde_yaml::to_value(json)
+                    .map_err(|e| FilterError::Config(format!("Failed to convert JSON: {}", e)))
+            }
+            ConfigFormat::Toml => {
+                let toml: toml::Table = toml::from_str(content)
+                    .map_err(|e| FilterError::Config(format!("Failed to parse TOML: {}", e)))?;
+                let mut value = serde_yaml::to_value(toml)
+                    .map_err(|e| FilterError::Config(format!("Failed to convert TOML: {}", e)))?;
+                named_tables_to_maps(&mut value)?;
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// Rewrites TOML's `[[filters]]`/`[[accounts]]` tables, which carry their
+/// name in a `name` key, into the `- name: {...}` shape the YAML uses.
+fn named_tables_to_maps(value: &mut Value) -> Result<()> {
+    let Value::Mapping(map) = value else {
+        return Ok(());
+    };
+    for key in CONCATENATED_KEYS {
+        let Some(Value::Sequence(tables)) = map.get_mut(*key) else {
+            continue;
+        };
+        for table in tables.iter_mut() {
+            let name = match table {
+                Value::Mapping(fields) => fields.remove("name"),
+                _ => None,
+            };
+            let Some(Value::String(name)) = name else {
+                return Err(FilterError::Config(format!(
+                    "Every [[{}]] table needs a string 'name'",
+                    key
+                )));
+            };
+            named_tables_to_maps(table)?;
+            let mut named = serde_yaml::Mapping::new();
+            named.insert(Value::String(name), std::mem::take(table));
+            *table = Value::Mapping(named);
+        }
+    }
+    Ok(())
+}
+
+/// Loads and merges `paths` in order, later files overriding earlier ones;
+/// `-` reads from `stdin`.
+pub fn load_configs_from<R: Read>(paths: &[PathBuf], mut stdin: R) -> Result<Config> {
+    let mut merged = Value::Mapping(Default::default());
+    for path in paths {
+        debug!("Loading configuration from {:?}", path);
+        let content = read_source(path, &mut stdin)?;
+        let value = ConfigFormat::from_path(path).parse(&content).map_err(|e| {
+            error!("{} in {}", e, path.display());
+            FilterError::Config(format!("{} in {}", e, path.display()))
+        })?;
+        merge_yaml(&mut merged, value, true);
+    }
+    interpolate_env(&mut merged, &|name| std::env::var(name).ok())
+        .inspect_err(|e| error!("{}", e))?;
+    check_accounts_stand_alone(&merged).inspect_err(|e| error!("{}", e))?;
+
+    let config: Config = serde_yaml::from_value(merged).map_err(|e| {
+        error!("Failed to parse YAML: {}", e);
+        FilterError::Config(format!("Failed to parse YAML: {}", e))
+    })?;
+
+    check_version(config.version).inspect_err(|e| error!("{}", e))?;
+
+    debug!("Successfully loaded configuration.");
+    debug!("Parsed config: {:?}", config);
+
+    Ok(config)
+}
+
+pub fn load_configs(paths: &[PathBuf]) -> Result<Config> {
+    load_configs_from(paths, io::stdin().lock())
+}
+
+pub fn load_config(path: &Path) -> Result<Config> {
+    load_configs(&[path.to_path_buf()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_debug_redacts_password() {
+        let config: Config = serde_yaml::from_str(
+            "imap_domain: imap.gmail.com\nimap_username: me@example.com\nimap_password: hunter2\nimap_password_command: 'echo hunter3'\nfilters: []\n",
+        )
+        .unwrap();
+
+        let output = format!("{:?}", config);
+        assert!(!output.contains("hunter2"), "password leaked: {}", output);
+        assert!(
+            !output.contains("hunter3"),
+            "password command leaked: {}",
+            output
+        );
+        assert!(output.contains("********"));
+        assert!(output.contains("me@example.com"));
+    }
+
+    #[test]
+    fn test_config_security_and_port() {
+        let config: Config = serde_yaml::from_str("filters: []\n").unwrap();
+        assert_eq!(config.security, Security::ImplicitTls);
+        assert_eq!(config.port, None);
+
+        let config: Config =
+            serde_yaml::from_str("security: starttls\nport: 1143\nfilters: []\n").unwrap();
+        assert_eq!(config.security, Security::Starttls);
+        assert_eq!(config.port, Some(1143));
+    }
+
+    #[test]
+    fn test_config_version_check() {
+        let version = |yaml: &str| serde_yaml::from_str::<Config>(yaml).unwrap().version;
+
+        assert_eq!(version("filters: []\n"), None);
+        assert!(check_version(version("filters: []\n")).is_ok());
+
+        assert!(check_version(version(&format!(
+            "version: {}\nfilters: []\n",
+            CONFIG_VERSION
+        )))
+        .is_ok());
+
+        let err = check_version(version(&format!(
+            "version: {}\nfilters: []\n",
+            CONFIG_VERSION + 1
+        )))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("newer than the supported"),
+            "{}",
+            err
+        );
+    }
+
+    const MULTI_ACCOUNT: &str = "\
+accounts:
+- work:
+    imap_domain: imap.gmail.com
+    imap_username: me@work.com
+    filters:
+    - boss:
+        from: boss@work.com
+        star: true
+- home:
+    imap_domain: imap.fastmail.com
+    imap_username: me@home.org
+    security: starttls
+    filters: []
+";
+
+    #[test]
+    fn test_multi_account_config_parses_in_order() {
+        let config: Config = serde_yaml::from_str(MULTI_ACCOUNT).unwrap();
+        let accounts = config.into_accounts(None).unwrap();
+
+        let names: Vec<_> = accounts.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["work", "home"]);
+        assert_eq!(accounts[0].1.filters[0].name, "boss");
+        assert_eq!(accounts[1].1.security, Security::Starttls);
+    }
+
+    #[test]
+    fn test_select_account_by_name() {
+        let config: Config = serde_yaml::from_str(MULTI_ACCOUNT).unwrap();
+        let accounts = config.into_accounts(Some("home")).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.imap_username.as_deref(), Some("me@home.org"));
+
+        let config: Config = serde_yaml::from_str(MULTI_ACCOUNT).unwrap();
+        let err = config.into_accounts(Some("typo")).unwrap_err();
+        assert!(matches!(err, FilterError::Config(_)), "{:?}", err);
+        assert!(err.to_string().contains("typo"));
+    }
+
+    #[test]
+    fn test_duplicate_account_names_are_rejected() {
+        let yaml = format!(
+            "{}- work:\n    imap_domain: imap.example.com\n",
+            MULTI_ACCOUNT
+        );
+        let config: Config = serde_yaml::from_str(&yaml).unwrap();
+        let err = config.into_accounts(Some("work")).unwrap_err();
+        assert!(matches!(err, FilterError::Config(_)), "{:?}", err);
+        assert!(err.to_string().contains("'work'"), "{}", err);
+    }
+
+    #[test]
+    fn test_top_level_settings_next_to_accounts_are_rejected() {
+        let mixed = temp_config(
+            "mixed.yml",
+            &format!("version: 1\nport: 1143\nfilters: []\n{}", MULTI_ACCOUNT),
+        );
+        let err = load_configs_from(std::slice::from_ref(&mixed), io::empty()).unwrap_err();
+        assert!(matches!(err, FilterError::Config(_)), "{:?}", err);
+        assert!(
+            err.to_string().contains("[\"port\", \"filters\"]"),
+            "{}",
+            err
+        );
+
+        let accounts_only = temp_config(
+            "accounts-only.yml",
+            &format!("version: 1\n{}", MULTI_ACCOUNT),
+        );
+        assert_eq!(
+            load_configs_from(std::slice::from_ref(&accounts_only), io::empty())
+                .unwrap()
+                .accounts
+                .len(),
+            2
+        );
+        fs::remove_file(mixed).unwrap();
+        fs::remove_file(accounts_only).unwrap();
+    }
+
+    #[test]
+    fn test_single_account_shorthand() {
+        let config: Config =
+            serde_yaml::from_str("imap_domain: imap.gmail.com\nfilters: []\n").unwrap();
+        let accounts = config.into_accounts(None).unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].0, DEFAULT_ACCOUNT);
+        assert_eq!(accounts[0].1.imap_domain.as_deref(), Some("imap.gmail.com"));
+    }
+
+    fn temp_config(file_name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("imap-filter-{}-{}", std::process::id(), file_name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_merged_configs_override_settings_and_concatenate_filters() {
+        let base = temp_config(
+            "base.yml",
+            "imap_domain: imap.gmail.com\nimap_username: shared@example.com\nfilter_strategy: all_match\nfilters:\n- team:\n    from: '*@example.com'\n",
+        );
+        let overlay = temp_config(
+            "overlay.yml",
+            "imap_username: me@example.com\nfilters:\n- mine:\n    to: me@example.com\n",
+        );
+
+        let config = load_configs_from(&[base.clone(), overlay.clone()], io::empty()).unwrap();
+
+        assert_eq!(config.imap_domain.as_deref(), Some("imap.gmail.com"));
+        assert_eq!(config.imap_username.as_deref(), Some("me@example.com"));
+        assert_eq!(config.filter_strategy, FilterStrategy::AllMatch);
+        let names: Vec<_> = config.filters.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["team", "mine"]);
+
+        fs::remove_file(base).unwrap();
+        fs::remove_file(overlay).unwrap();
+    }
+
+    #[test]
+    fn test_json_config_matches_yaml() {
+        let yaml = temp_config(
+            "format.yml",
+            "version: 1\nimap_domain: imap.gmail.com\nsecurity: starttls\nfilters:\n- only-me:\n    to: ['me@example.com']\n    move_to: Imbox\n    star: true\nfolders:\n  Imbox:\n    read: 7d\n",
+        );
+        let json = yaml.with_extension("json");
+        fs::write(
+            &json,
+            r#"{"version": 1, "imap_domain": "imap.gmail.com", "security": "starttls",
+                "filters": [{"only-me": {"to": ["me@example.com"], "move_to": "Imbox", "star": true}}],
+                "folders": {"Imbox": {"read": "7d"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(ConfigFormat::from_path(&json), ConfigFormat::Json);
+
+        let from_yaml = load_config(&yaml).unwrap();
+        let from_json = load_config(&json).unwrap();
+        assert_eq!(format!("{:?}", from_yaml), format!("{:?}", from_json));
+
+        fs::remove_file(yaml).unwrap();
+        fs::remove_file(json).unwrap();
+    }
+
+    #[test]
+    fn test_toml_config_uses_named_tables() {
+        let path = temp_config(
+            "format.toml",
+            r#"
+version = 1
+
+[[accounts]]
+name = "work"
+imap_domain = "imap.gmail.com"
+security = "starttls"
+
+[[accounts.filters]]
+name = "only-me"
+to = ["me@example.com"]
+move_to = "Imbox"
+max_size = "5MB"
+
+[[accounts]]
+name = "home"
+imap_domain = "imap.fastmail.com"
+
+[accounts.folders.Imbox]
+read = "7d"
+"#,
+        );
+
+        let config = load_config(&path).unwrap();
+        let accounts = config.into_accounts(None).unwrap();
+
+        let names: Vec<_> = accounts.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["work", "home"]);
+        let work = &accounts[0].1;
+        assert_eq!(work.security, Security::Starttls);
+        assert_eq!(work.filters[0].name, "only-me");
+        assert_eq!(
+            work.filters[0]
+                .move_to
+                .as_ref()
+                .map(|target| target.folder.as_str()),
+            Some("Imbox")
+        );
+        assert_eq!(work.filters[0].max_size, Some(5 * 1024 * 1024));
+        let home = &accounts[1].1;
+        assert_eq!(
+            home.folders.as_ref().unwrap()["Imbox"].read.as_deref(),
+            Some("7d")
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_toml_filter_without_name_is_an_error() {
+        let err = ConfigFormat::Toml
+            .parse("[[filters]]\nfrom = \"*@example.com\"\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("[[filters]]"), "{}", err);
+    }
+
+    #[test]
+    fn test_config_from_stdin() {
+        let stdin =
+            io::Cursor::new("imap_domain: imap.example.com\nfilters:\n- all:\n    from: '*'\n");
+        let config = load_configs_from(&[PathBuf::from("-")], stdin).unwrap();
+
+        assert_eq!(config.imap_domain.as_deref(), Some("imap.example.com"));
+        assert_eq!(config.filters.len(), 1);
+    }
+
+    #[test]
+    fn test_example_config_loads() {
+        let config =
+            load_config(&Path::new(env!("CARGO_MANIFEST_DIR")).join("imap-filter.yml")).unwrap();
+
+        assert_eq!(config.version, Some(CONFIG_VERSION));
+        assert_eq!(config.imap_domain.as_deref(), Some("imap.gmail.com"));
+        assert!(config.imap_username.is_some());
+        let names: Vec<_> = config.filters.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["only-me-star", "only-me"]);
+        for filter in &config.filters {
+            assert_eq!(
+                filter.move_to.as_ref().map(|target| target.folder.as_str()),
+                Some("Imbox")
+            );
+        }
+        assert_eq!(config.filters[0].star, Some(true));
+    }
+
+    #[test]
+    fn test_interpolate_env_expands_credentials_and_folders() {
+        let lookup = |name: &str| (name == "IMAP_PASSWORD").then(|| "hunter2".to_string());
+        let mut value: Value = serde_yaml::from_str(
+            "imap_password: ${IMAP_PASSWORD}\nimap_username: ${IMAP_USER:-me@example.com}\nfilters:\n- a:\n    from: '${NOT_EXPANDED}'\n    move_to: ${FOLDER:-Archive}/old\n",
+        )
+        .unwrap();
+
+        interpolate_env(&mut value, &lookup).unwrap();
+        let config: Config = serde_yaml::from_value(value).unwrap();
+
+        assert_eq!(config.imap_password.as_deref(), Some("hunter2"));
+        assert_eq!(config.imap_username.as_deref(), Some("me@example.com"));
+        assert_eq!(
+            config.filters[0]
+                .move_to
+                .as_ref()
+                .map(|target| target.folder.as_str()),
+            Some("Archive/old")
+        );
+        assert_eq!(
+            config.filters[0].from.as_ref().unwrap().patterns,
+            vec!["${NOT_EXPANDED}"]
+        );
+    }
+
+    #[test]
+    fn test_interpolate_env_missing_variable_is_an_error() {
+        let mut value: Value = serde_yaml::from_str("imap_password: ${IMAP_PASSWORD}\n").unwrap();
+        let err = interpolate_env(&mut value, &|_: &str| None).unwrap_err();
+        assert!(err.to_string().contains("IMAP_PASSWORD"), "{}", err);
+    }
+
+    #[test]
+    fn test_expand_vars_default_covers_empty_values() {
+        let lookup = |name: &str| (name == "IMAP_PASSWORD").then(String::new);
+        assert_eq!(
+            expand_vars("${IMAP_PASSWORD:-fallback}", &lookup).unwrap(),
+            "fallback"
+        );
+        assert_eq!(expand_vars("${IMAP_PASSWORD}", &lookup).unwrap(), "");
+    }
+
+    #[test]
+    fn test_invalid_glob_pattern_fails_to_load() {
+        let err =
+            serde_yaml::from_str::<Config>("filters:\n- broken:\n    from: '[oops@example.com'\n")
+                .unwrap_err();
+        assert!(err.to_string().contains("[oops@example.com"), "{}", err);
+    }
+
+    #[test]
+    fn test_filters_are_named_from_map_keys() {
+        let config: Config = serde_yaml::from_str(
+            "filters:\n- only-me:\n    to: ['me@example.com']\n- team:\n    from: '*@example.com'\n",
+        )
+        .unwrap();
+
+        let names: Vec<_> = config.filters.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["only-me", "team"]);
+    }
+
+    #[test]
+    fn test_filters_keep_yaml_order_within_one_list_item() {
+        let names = [
+            "zeta", "alpha", "mid", "beta", "omega", "gamma", "delta", "kappa",
+        ];
+        let yaml: String = names
+            .iter()
+            .map(|name| format!("  {}:\n    from: '*@{}.com'\n", name, name))
+            .collect();
+        let config: Config = serde_yaml::from_str(&format!(
+            "filters:\n- {}- last:\n    from: '*'\n",
+            &yaml[2..]
+        ))
+        .unwrap();
+
+        let parsed: Vec<_> = config.filters.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(
+            parsed,
+            names.iter().copied().chain(["last"]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_default_filter_action_runs_last() {
+        let yaml = "default_filter_action:\n  move_to: Triage\nfilters:\n- work:\n    from: '*@tatari.tv'\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let (_, account) = config.into_accounts(None).unwrap().remove(0);
+
+        let names: Vec<_> = account
+            .filters
+            .iter()
+            .map(|f| (f.name.as_str(), f.catch_all))
+            .collect();
+        assert_eq!(names, vec![("work", false), (DEFAULT_FILTER_NAME, true)]);
+        assert_eq!(
+            account.filters[1]
+                .move_to
+                .as_ref()
+                .map(|target| target.folder.as_str()),
+            Some("Triage")
+        );
+        assert!(account.default_filter_action.is_none());
+    }
+
+    #[test]
+    fn test_unanchored_filter_matches_substrings() {
+        let yaml = "filters:\n- work:\n    from: tatari.tv\n    subject: invoice\n    anchored: false\n- strict:\n    from: tatari.tv\n";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let from = |filter: &MessageFilter| {
+            filter
+                .from
+                .as_ref()
+                .unwrap()
+                .matches(&["scott@tatari.tv".to_string()])
+        };
+
+        assert!(from(&config.filters[0]));
+        assert!(config.filters[0].subject.matches("Your invoice is ready"));
+        assert!(!from(&config.filters[1]));
+    }
+}
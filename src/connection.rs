@@ -0,0 +1,353 @@
+use crate::error::{FilterError, Result};
+use log::{debug, warn};
+use native_tls::{TlsConnector, TlsStream};
+use serde::Deserialize;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+const STARTTLS_TAG: &str = "a0";
+
+/// How the connection to the IMAP server is secured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Security {
+    /// TLS from the first byte, conventionally on port 993.
+    #[default]
+    ImplicitTls,
+    /// Plaintext connection upgraded with the STARTTLS command, conventionally on port 143.
+    Starttls,
+    /// No encryption at all; credentials are sent in the clear.
+    Plain,
+}
+
+impl Security {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Security::ImplicitTls => 993,
+            Security::Starttls | Security::Plain => 143,
+        }
+    }
+}
+
+/// Socket-level settings for the IMAP connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    pub security: Security,
+    pub port: Option<u16>,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            security: Security::default(),
+            port: None,
+            connect_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            read_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// The configured port, or the conventional port for the security mode.
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.security.default_port())
+    }
+}
+
+/// The transport underneath the IMAP session, either encrypted or not.
+#[derive(Debug)]
+pub enum ImapStream {
+    Tls(Box<TlsStream<TcpStream>>),
+    Plain(TcpStream),
+}
+
+impl Read for ImapStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ImapStream::Tls(stream) => stream.read(buf),
+            ImapStream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ImapStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ImapStream::Tls(stream) => stream.write(buf),
+            ImapStream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ImapStream::Tls(stream) => stream.flush(),
+            ImapStream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A socket error before the IMAP session exists.
+fn lost(e: io::Error) -> FilterError {
+    FilterError::Connection(format!("IMAP connection failed: {}", e))
+}
+
+/// Connects to the first of `addrs` that accepts within `timeout`, so an
+/// unreachable AAAA record listed first doesn't hide a working IPv4 one.
+/// Fails with the last address's error when none connect.
+fn connect_any(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => {
+                debug!("IMAP connection to {} failed: {}", addr, e);
+                last_error = Some(FilterError::Connection(format!(
+                    "IMAP connection to {} failed: {}",
+                    addr, e
+                )));
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| FilterError::Connection("No address to connect to".to_string())))
+}
+
+/// Opens a TCP connection with the configured timeouts applied before any
+/// handshake, so an unresponsive server fails instead of hanging.
+fn open_tcp(domain: &str, settings: &ConnectionSettings) -> Result<TcpStream> {
+    let port = settings.port();
+    let addrs: Vec<SocketAddr> = (domain, port)
+        .to_socket_addrs()
+        .map_err(|e| {
+            FilterError::Connection(format!("Could not resolve {}:{}: {}", domain, port, e))
+        })?
+        .collect();
+    if addrs.is_empty() {
+        return Err(FilterError::Connection(format!(
+            "Could not resolve {}:{}",
+            domain, port
+        )));
+    }
+
+    let tcp = connect_any(addrs, settings.connect_timeout)?;
+    tcp.set_read_timeout(Some(settings.read_timeout))
+        .map_err(lost)?;
+    tcp.set_write_timeout(Some(settings.read_timeout))
+        .map_err(lost)?;
+
+    Ok(tcp)
+}
+
+fn tls_handshake(domain: &str, tcp: TcpStream) -> Result<ImapStream> {
+    let tls = TlsConnector::builder()
+        .build()
+        .map_err(|e| FilterError::Connection(format!("TLS setup failed: {}", e)))?;
+    let stream = tls.connect(domain, tcp).map_err(|e| {
+        FilterError::Connection(format!("TLS handshake with {} failed: {}", domain, e))
+    })?;
+    Ok(ImapStream::Tls(Box::new(stream)))
+}
+
+/// Reads a single CRLF-terminated line a byte at a time, so nothing beyond
+/// the line is consumed from the socket before the TLS upgrade.
+fn read_line<S: Read>(stream: &mut S) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte).map_err(lost)? == 0 {
+            return Err(FilterError::Connection(
+                "Connection closed during STARTTLS negotiation".to_string(),
+            ));
+        }
+        line.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+/// Consumes the server greeting and issues STARTTLS on a plaintext stream,
+/// leaving it ready for the TLS handshake.
+pub fn negotiate_starttls<S: Read + Write>(stream: &mut S) -> Result<()> {
+    let greeting = read_line(stream)?;
+    debug!("IMAP greeting: {}", greeting);
+    if !greeting.starts_with("* OK") {
+        return Err(FilterError::Connection(format!(
+            "Unexpected IMAP greeting: {}",
+            greeting
+        )));
+    }
+
+    write!(stream, "{} STARTTLS\r\n", STARTTLS_TAG).map_err(lost)?;
+    stream.flush().map_err(lost)?;
+
+    loop {
+        let line = read_line(stream)?;
+        if let Some(status) = line.strip_prefix(&format!("{} ", STARTTLS_TAG)) {
+            return if status.starts_with("OK") {
+                Ok(())
+            } else {
+                Err(FilterError::Connection(format!(
+                    "Server refused STARTTLS: {}",
+                    status
+                )))
+            };
+        }
+    }
+}
+
+/// Connects to the server using the configured security mode and returns a
+/// client ready to log in.
+pub fn connect(domain: &str, settings: &ConnectionSettings) -> Result<imap::Client<ImapStream>> {
+    let mut tcp = open_tcp(domain, settings)?;
+
+    let (stream, greeted) = match settings.security {
+        Security::ImplicitTls => (tls_handshake(domain, tcp)?, false),
+        Security::Starttls => {
+            negotiate_starttls(&mut tcp)?;
+            (tls_handshake(domain, tcp)?, true)
+        }
+        Security::Plain => {
+            warn!(
+                "Connecting to {} without encryption; credentials will be sent in the clear",
+                domain
+            );
+            (ImapStream::Plain(tcp), false)
+        }
+    };
+
+    let mut client = imap::Client::new(stream);
+    if !greeted {
+        client.read_greeting().map_err(|e| {
+            FilterError::Connection(format!("IMAP greeting from {} failed: {}", domain, e))
+        })?;
+    }
+
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_security_deserialize() {
+        assert_eq!(
+            serde_yaml::from_str::<Security>("implicit_tls").unwrap(),
+            Security::ImplicitTls
+        );
+        assert_eq!(
+            serde_yaml::from_str::<Security>("starttls").unwrap(),
+            Security::Starttls
+        );
+        assert_eq!(
+            serde_yaml::from_str::<Security>("plain").unwrap(),
+            Security::Plain
+        );
+        assert!(serde_yaml::from_str::<Security>("ssl").is_err());
+    }
+
+    #[test]
+    fn test_port_selection() {
+        let mut settings = ConnectionSettings::default();
+        assert_eq!(settings.port(), 993);
+
+        settings.security = Security::Starttls;
+        assert_eq!(settings.port(), 143);
+
+        settings.security = Security::Plain;
+        assert_eq!(settings.port(), 143);
+
+        settings.port = Some(1143);
+        assert_eq!(settings.port(), 1143);
+    }
+
+    fn fake_server(reply: &'static str) -> (u16, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.write_all(b"* OK IMAP4rev1 ready\r\n").unwrap();
+            let mut command = String::new();
+            BufReader::new(socket.try_clone().unwrap())
+                .read_line(&mut command)
+                .unwrap();
+            socket.write_all(reply.as_bytes()).unwrap();
+            command
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_negotiate_starttls_accepted() {
+        let (port, server) = fake_server("a0 OK Begin TLS negotiation now\r\n");
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        assert!(negotiate_starttls(&mut stream).is_ok());
+        assert_eq!(server.join().unwrap(), "a0 STARTTLS\r\n");
+    }
+
+    #[test]
+    fn test_negotiate_starttls_refused() {
+        let (port, server) = fake_server("a0 BAD STARTTLS not supported\r\n");
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+        let err = negotiate_starttls(&mut stream).unwrap_err();
+        assert!(matches!(err, FilterError::Connection(_)), "{:?}", err);
+        assert!(err.to_string().contains("refused STARTTLS"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_any_falls_through_to_a_working_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let tcp = connect_any([closed, open], Duration::from_secs(1)).unwrap();
+        assert_eq!(tcp.peer_addr().unwrap(), open);
+
+        let err = connect_any([closed], Duration::from_secs(1)).unwrap_err();
+        assert!(err.to_string().contains(&closed.to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_connect_times_out_on_unresponsive_server() {
+        // Accepts the TCP connection (via the listen backlog) but never speaks TLS
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = ConnectionSettings {
+            port: Some(listener.local_addr().unwrap().port()),
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let err = connect("127.0.0.1", &settings).unwrap_err();
+
+        // The TCP connect succeeds; it's the handshake that must give up
+        assert!(
+            err.to_string().contains("TLS handshake"),
+            "expected a handshake timeout, got {}",
+            err
+        );
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "connection attempt hung for {:?}",
+            start.elapsed()
+        );
+        drop(listener);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::{FilterError, Result};
+use log::debug;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Masks a secret so it can be safely included in debug output.
+pub fn redact(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| "********")
+}
+
+/// Everything needed to log in to the IMAP server.
+pub struct Credentials {
+    pub domain: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("domain", &self.domain)
+            .field("username", &self.username)
+            .field("password", &"********")
+            .finish()
+    }
+}
+
+/// Every place an IMAP password can come from, in precedence order:
+/// CLI flag > password command > password file > environment > config file.
+#[derive(Default)]
+pub struct PasswordSources {
+    pub flag: Option<String>,
+    pub command: Option<String>,
+    pub file: Option<PathBuf>,
+    pub env: Option<String>,
+    pub config: Option<String>,
+}
+
+/// Runs `command` through the shell and uses its stdout as the password,
+/// like git's `credential.helper`.
+fn password_from_command(command: &str) -> Result<String> {
+    debug!("Reading IMAP password from command");
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| FilterError::Config(format!("Failed to run password command: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(FilterError::Config(format!(
+            "Password command exited with {}",
+            output.status
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+fn password_from_file(path: &PathBuf) -> Result<String> {
+    debug!("Reading IMAP password from {}", path.display());
+    let content = fs::read_to_string(path).map_err(|e| {
+        FilterError::Config(format!(
+            "Failed to read password file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    Ok(content.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Resolves the password from the highest-precedence source that is set.
+/// Lower-precedence sources are never consulted, so a password command is
+/// not run when the password was given on the command line.
+pub fn resolve_password(sources: PasswordSources) -> Result<String> {
+    if let Some(password) = sources.flag {
+        return Ok(password);
+    }
+    if let Some(command) = &sources.command {
+        return password_from_command(command);
+    }
+    if let Some(path) = &sources.file {
+        return password_from_file(path);
+    }
+    sources
+        .env
+        .or(sources.config)
+        .ok_or_else(|| FilterError::Config("IMAP password is required".to_string()))
+}
+
+/// Fails unless the account about to be logged in to is `expected`
+/// (compared case-insensitively), so a stale `IMAP_USERNAME` or `--account`
+/// mix-up can't point destructive filters at the wrong mailbox.
+pub fn confirm_account(expected: &str, username: &str) -> Result<()> {
+    if expected.trim().eq_ignore_ascii_case(username.trim()) {
+        return Ok(());
+    }
+    Err(FilterError::Config(format!(
+        "Refusing to run: logging in as '{}' but confirm_account expects '{}'",
+        username, expected
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_password_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("imap-filter-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_confirm_account() {
+        assert!(confirm_account("me@example.com", "me@example.com").is_ok());
+        assert!(confirm_account("Me@Example.com ", "me@example.com").is_ok());
+
+        let err = confirm_account("me@example.com", "someone-else@example.com").unwrap_err();
+        assert!(matches!(err, FilterError::Config(_)), "{:?}", err);
+        assert!(err.to_string().contains("someone-else@example.com"));
+        assert!(confirm_account("me@example.com", "me").is_err());
+    }
+
+    #[test]
+    fn test_flag_beats_everything() {
+        let sources = PasswordSources {
+            flag: Some("flag".to_string()),
+            command: Some("echo command".to_string()),
+            file: Some(PathBuf::from("/nonexistent")),
+            env: Some("env".to_string()),
+            config: Some("config".to_string()),
+        };
+        assert_eq!(resolve_password(sources).unwrap(), "flag");
+    }
+
+    #[test]
+    fn test_command_beats_file_env_and_config() {
+        let sources = PasswordSources {
+            command: Some("echo command".to_string()),
+            file: Some(PathBuf::from("/nonexistent")),
+            env: Some("env".to_string()),
+            config: Some("config".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_password(sources).unwrap(), "command");
+    }
+
+    #[test]
+    fn test_file_beats_env_and_config() {
+        let path = temp_password_file("file-precedence", "file\n");
+        let sources = PasswordSources {
+            file: Some(path.clone()),
+            env: Some("env".to_string()),
+            config: Some("config".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_password(sources).unwrap(), "file");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_env_beats_config() {
+        let sources = PasswordSources {
+            env: Some("env".to_string()),
+            config: Some("config".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_password(sources).unwrap(), "env");
+    }
+
+    #[test]
+    fn test_config_is_last_resort() {
+        let sources = PasswordSources {
+            config: Some("config".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve_password(sources).unwrap(), "config");
+        assert!(resolve_password(PasswordSources::default()).is_err());
+    }
+
+    #[test]
+    fn test_failing_command_is_an_error() {
+        let sources = PasswordSources {
+            command: Some("exit 3".to_string()),
+            config: Some("config".to_string()),
+            ..Default::default()
+        };
+        assert!(resolve_password(sources).is_err());
+    }
+}
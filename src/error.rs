@@ -0,0 +1,46 @@
+use thiserror::Error;
+
+/// Everything the library can fail with, so embedders can match on the kind
+/// of failure instead of parsing messages. The binary wraps these in `eyre`.
+#[derive(Debug, Error)]
+pub enum FilterError {
+    /// Resolving, connecting to, or negotiating TLS with the server.
+    #[error("{0}")]
+    Connection(String),
+    /// The server rejected the credentials.
+    #[error("IMAP authentication failed: {0}")]
+    Auth(String),
+    /// A config file, account, filter, or password source that is missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// An IMAP command failed, or the session dropped.
+    #[error(transparent)]
+    Imap(#[from] imap::Error),
+    /// An `older_than`/`newer_than` age that doesn't parse.
+    #[error("{0}")]
+    InvalidDuration(String),
+    /// A search query the server would reject or misread.
+    #[error("{0}")]
+    InvalidQuery(String),
+    /// The server lacks a capability the config relies on.
+    #[error("{0}")]
+    Unsupported(String),
+    /// Reading or writing a local file such as a report or last-run stamp.
+    #[error("{0}")]
+    Io(String),
+    /// An action failed and `fail_fast` stopped the run.
+    #[error("{message}")]
+    Action {
+        message: String,
+        #[source]
+        source: Box<FilterError>,
+    },
+}
+
+impl From<std::io::Error> for FilterError {
+    fn from(e: std::io::Error) -> Self {
+        FilterError::Io(e.to_string())
+    }
+}
+
+pub type Result<T, E = FilterError> = std::result::Result<T, E>;
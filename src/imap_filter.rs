@@ -1,52 +1,873 @@
-use eyre::{Result, eyre};
+use crate::error::{FilterError, Result};
+use chrono::{DateTime, NaiveDate, Utc};
 use imap::Session;
-use log::{debug, info, error};
-use native_tls::{TlsConnector, TlsStream};
-use std::net::TcpStream;
-use imap::types::Flag; // Import Flag type for correct comparison
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::message::Message;
-pub use crate::message_filter::MessageFilter;
 use crate::address_filter::AddressFilter;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::client::{expunge_deleted, uid_move_copy, FetchedMessage, ImapOps};
+use crate::connection::{self, ConnectionSettings, ImapStream};
+use crate::credentials::Credentials;
+use crate::labels::quote_label;
+use crate::log_style::{scope, LogStyle, Marker};
+use crate::message::{AgeBasis, Message};
+pub use crate::message_filter::{MessageFilter, DEFAULT_MAILBOX};
+use crate::query::{build_search_query, validate_imap_query, DEFAULT_INBOX_QUERY};
+use crate::report::ActionRecord;
+
+pub const DEFAULT_PROGRESS_INTERVAL: usize = 500;
+pub const DEFAULT_FETCH_CHUNK_SIZE: usize = 500;
+/// How many times its previous run's actions a filter may take before it
+/// draws a warning.
+pub const DEFAULT_SPIKE_FACTOR: f64 = 5.0;
+
+/// How messages flow between filters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterStrategy {
+    /// A message is consumed by the first filter it matches.
+    #[default]
+    FirstMatch,
+    /// Every filter a message matches is applied to it, until a move takes
+    /// it out of the mailbox (anywhere but Gmail, where moves are labels).
+    AllMatch,
+}
+
+/// Run-wide knobs that shape how filters are evaluated.
+#[derive(Debug, Clone)]
+pub struct FilterOptions {
+    pub strategy: FilterStrategy,
+    pub age_basis: AgeBasis,
+    /// Create missing Move targets before running instead of only warning.
+    pub create_folders: bool,
+    pub log_style: LogStyle,
+    /// From addresses that are never moved or starred, whatever the filters say.
+    pub never_touch: AddressFilter,
+    /// SEARCH criteria selecting which INBOX messages are fetched.
+    pub inbox_query: String,
+    /// Gmail search syntax, sent as `X-GM-RAW` alongside `inbox_query`.
+    pub gmail_search: Option<String>,
+    /// Only fetch messages that reference (or reply to) this Message-ID.
+    pub references: Option<String>,
+    /// Only fetch messages that arrived on or after this date.
+    pub since: Option<NaiveDate>,
+    /// Act only on the first copy of messages sharing a Message-ID.
+    pub dedupe_by_message_id: bool,
+    /// Abort the run on the first failed action instead of logging and continuing.
+    pub fail_fast: bool,
+    /// Process at most this many messages (the lowest sequence numbers).
+    pub limit: Option<usize>,
+    /// Log progress every this many messages; 0 disables progress lines.
+    pub progress_interval: usize,
+    /// Fetch at most this many messages per FETCH command.
+    pub fetch_chunk_size: usize,
+    /// Pause between mutating commands (move/star); zero disables it.
+    pub throttle: Duration,
+    /// Stop starting new actions once the run has taken this long.
+    pub max_runtime: Option<Duration>,
+    /// Log a full trace of how every filter judged the message with this UID.
+    pub debug_uid: Option<u32>,
+    /// Warn when a filter takes more than this many times the actions it
+    /// took on the previous run.
+    pub spike_factor: f64,
+}
+
+impl Default for FilterOptions {
+    fn default() -> Self {
+        Self {
+            strategy: FilterStrategy::default(),
+            age_basis: AgeBasis::default(),
+            create_folders: true,
+            log_style: LogStyle::default(),
+            never_touch: AddressFilter::default(),
+            inbox_query: DEFAULT_INBOX_QUERY.to_string(),
+            gmail_search: None,
+            references: None,
+            since: None,
+            dedupe_by_message_id: false,
+            fail_fast: false,
+            limit: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            fetch_chunk_size: DEFAULT_FETCH_CHUNK_SIZE,
+            throttle: Duration::ZERO,
+            max_runtime: None,
+            debug_uid: None,
+            spike_factor: DEFAULT_SPIKE_FACTOR,
+        }
+    }
+}
+
+impl FilterOptions {
+    /// One `key: value` line per option, defaults included.
+    pub fn details(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("strategy: {:?}", self.strategy),
+            format!("age_basis: {:?}", self.age_basis),
+            format!("create_folders: {}", self.create_folders),
+            format!("log_style: {:?}", self.log_style),
+            format!("never_touch: {:?}", self.never_touch.patterns),
+            format!("inbox_query: {}", self.inbox_query),
+        ];
+        if let Some(gmail_search) = &self.gmail_search {
+            lines.push(format!("gmail_search: {}", gmail_search));
+        }
+        if let Some(references) = &self.references {
+            lines.push(format!("references: {}", references));
+        }
+        if let Some(since) = self.since {
+            lines.push(format!("since: {}", since));
+        }
+        lines.push(format!(
+            "dedupe_by_message_id: {}",
+            self.dedupe_by_message_id
+        ));
+        lines.push(format!("fail_fast: {}", self.fail_fast));
+        match self.limit {
+            Some(limit) => lines.push(format!("limit: {}", limit)),
+            None => lines.push("limit: None".to_string()),
+        }
+        lines.push(format!("progress_interval: {}", self.progress_interval));
+        lines.push(format!("fetch_chunk_size: {}", self.fetch_chunk_size));
+        lines.push(format!("throttle: {}ms", self.throttle.as_millis()));
+        if let Some(max_runtime) = self.max_runtime {
+            lines.push(format!("max_runtime: {}s", max_runtime.as_secs()));
+        }
+        if let Some(uid) = self.debug_uid {
+            lines.push(format!("debug_uid: {}", uid));
+        }
+        lines.push(format!("spike_factor: {}", self.spike_factor));
+        lines
+    }
+
+    pub fn print_details(&self) {
+        println!("\noptions");
+        for line in self.details() {
+            println!("    {}", line);
+        }
+    }
+}
+
+/// Process exit codes, from least to most severe apart from `EXIT_FATAL`.
+pub const EXIT_NOTHING_TO_DO: i32 = 0;
+pub const EXIT_ACTIONS_APPLIED: i32 = 10;
+pub const EXIT_ACTION_ERRORS: i32 = 20;
+/// `max_runtime` ran out before every message was processed.
+pub const EXIT_TIMED_OUT: i32 = 30;
+/// A run that could not complete, e.g. a bad config or a failed login.
+pub const EXIT_FATAL: i32 = 1;
+
+/// What a run did, returned from `execute`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub fetched: usize,
+    pub matched: usize,
+    /// Move/star actions that succeeded.
+    pub applied: usize,
+    /// Move/star actions that failed and were skipped.
+    pub errors: usize,
+    /// The run was stopped early by the stop flag or `max_runtime`.
+    pub interrupted: bool,
+    /// The run stopped early because `max_runtime` ran out.
+    pub timed_out: bool,
+    /// Per-filter counts, in filter order.
+    pub filters: Vec<FilterCounts>,
+    /// Every move/star attempted, in order.
+    pub actions: Vec<ActionRecord>,
+}
+
+/// What one filter matched and did during a run.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilterCounts {
+    pub name: String,
+    pub matched: usize,
+    pub moved: usize,
+    pub appended: usize,
+    pub starred: usize,
+    pub failed: usize,
+}
+
+impl FilterCounts {
+    /// Moves, appends and stars, successful or not.
+    pub fn actions(&self) -> usize {
+        self.moved + self.appended + self.starred + self.failed
+    }
+}
+
+impl fmt::Display for FilterCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "filter '{}': matched {}", self.name, self.matched)?;
+        for (label, count) in [
+            ("moved", self.moved),
+            ("appended", self.appended),
+            ("starred", self.starred),
+            ("failed", self.failed),
+        ] {
+            if count > 0 {
+                write!(f, ", {} {}", label, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Summary {
+    /// Maps a completed run to its exit code: running out of time wins over
+    /// any failed action, which wins over applied actions, which win over a
+    /// run with nothing to do.
+    pub fn exit_code(&self) -> i32 {
+        if self.timed_out {
+            EXIT_TIMED_OUT
+        } else if self.errors > 0 {
+            EXIT_ACTION_ERRORS
+        } else if self.applied > 0 {
+            EXIT_ACTIONS_APPLIED
+        } else {
+            EXIT_NOTHING_TO_DO
+        }
+    }
+
+    /// Adds the counts and records of another mailbox's pass to this one.
+    fn absorb(&mut self, other: Summary) {
+        self.fetched += other.fetched;
+        self.matched += other.matched;
+        self.applied += other.applied;
+        self.errors += other.errors;
+        self.interrupted |= other.interrupted;
+        self.timed_out |= other.timed_out;
+        self.filters.extend(other.filters);
+        self.actions.extend(other.actions);
+    }
+
+    /// Actions taken per filter name, summed over every mailbox.
+    pub fn action_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for filter in &self.filters {
+            *counts.entry(filter.name.clone()).or_insert(0) += filter.actions();
+        }
+        counts
+    }
+}
+
+/// A filter that took far more actions than on the previous run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionSpike {
+    pub filter: String,
+    pub previous: usize,
+    pub current: usize,
+}
+
+/// The filters in `current` whose counts exceed `factor` times their count
+/// in `previous`; a previous count of 0 is treated as 1 so a filter that
+/// suddenly wakes up is caught too. Filters new since the previous run
+/// have nothing to compare against and are left out.
+pub fn action_spikes(
+    previous: &BTreeMap<String, usize>,
+    current: &BTreeMap<String, usize>,
+    factor: f64,
+) -> Vec<ActionSpike> {
+    current
+        .iter()
+        .filter_map(|(filter, &current)| {
+            let previous = *previous.get(filter)?;
+            (current as f64 > previous.max(1) as f64 * factor).then(|| ActionSpike {
+                filter: filter.clone(),
+                previous,
+                current,
+            })
+        })
+        .collect()
+}
+
+/// What's fetched for each message. `BODY.PEEK[]` rather than `RFC822` so
+/// fetching doesn't mark messages read and FLAGS reports the real `\Seen`.
+pub const FETCH_ITEMS: &str = "(UID FLAGS BODY.PEEK[] RFC822.SIZE INTERNALDATE)";
+
+/// Capability advertised by Gmail for its X-GM-LABELS/X-GM-RAW extensions.
+pub const GMAIL_CAPABILITY: &str = "X-GM-EXT-1";
+
+/// How a Move action is carried out, chosen from the server's capabilities.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MoveMethod {
+    /// Gmail: add the destination as a label.
+    #[default]
+    GmailLabel,
+    /// RFC 6851 `UID MOVE`.
+    Move,
+    /// `UID COPY`, flag the original `\Deleted`, then expunge it; with
+    /// `uidplus` only the moved UIDs are expunged.
+    CopyExpunge { uidplus: bool },
+}
+
+impl MoveMethod {
+    pub fn select(capabilities: &HashSet<String>) -> Self {
+        if capabilities.contains(GMAIL_CAPABILITY) {
+            MoveMethod::GmailLabel
+        } else if capabilities.contains("MOVE") {
+            MoveMethod::Move
+        } else {
+            MoveMethod::CopyExpunge {
+                uidplus: capabilities.contains("UIDPLUS"),
+            }
+        }
+    }
+
+    /// Moves one message into `destination`.
+    pub fn apply<C: ImapOps>(self, client: &mut C, uid: u32, destination: &str) -> Result<()> {
+        let uid = uid.to_string();
+        match self {
+            // Gmail "moves" by labeling, which leaves the message in INBOX
+            MoveMethod::GmailLabel => {
+                client.uid_store(&uid, &format!("+X-GM-LABELS {}", quote_label(destination)))
+            }
+            MoveMethod::Move => client.uid_mv(&uid, destination),
+            MoveMethod::CopyExpunge { uidplus } => {
+                uid_move_copy(client, &uid, destination, uidplus)
+            }
+        }
+    }
+
+    /// Moves one message again after the session dropped partway through
+    /// `apply`. A copy-and-expunge move must not copy twice, so it checks
+    /// the source first: a message that is gone needs nothing more, and one
+    /// already flagged `\Deleted` was copied and only needs expunging.
+    pub fn resume<C: ImapOps>(self, client: &mut C, uid: u32, destination: &str) -> Result<()> {
+        let MoveMethod::CopyExpunge { uidplus } = self else {
+            return self.apply(client, uid, destination);
+        };
+        let uid_set = uid.to_string();
+        match client.uid_fetch(&uid_set, "FLAGS")?.first() {
+            None => Ok(()),
+            Some(msg) if msg.flags.iter().any(|flag| flag == "\\Deleted") => {
+                expunge_deleted(client, &uid_set, uidplus)
+            }
+            Some(_) => uid_move_copy(client, &uid_set, destination, uidplus),
+        }
+    }
+
+    /// True when a move takes the message out of the mailbox it was read
+    /// from, so its UID is gone for any later action there.
+    pub fn removes_from_source(self) -> bool {
+        !matches!(self, MoveMethod::GmailLabel)
+    }
+
+    /// Files one message under `destination` but leaves it where it is: a
+    /// label on Gmail, a plain `UID COPY` elsewhere.
+    pub fn apply_keeping_source<C: ImapOps>(
+        self,
+        client: &mut C,
+        uid: u32,
+        destination: &str,
+    ) -> Result<()> {
+        match self {
+            MoveMethod::GmailLabel => self.apply(client, uid, destination),
+            MoveMethod::Move | MoveMethod::CopyExpunge { .. } => {
+                client.uid_copy(&uid.to_string(), destination)
+            }
+        }
+    }
+}
+
+/// How a Star action is carried out: Gmail's `\Starred` label, or the
+/// standard `\Flagged` system flag elsewhere.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StarMethod {
+    #[default]
+    GmailLabel,
+    Flagged,
+}
+
+impl StarMethod {
+    pub fn select(capabilities: &HashSet<String>) -> Self {
+        if capabilities.contains(GMAIL_CAPABILITY) {
+            StarMethod::GmailLabel
+        } else {
+            StarMethod::Flagged
+        }
+    }
+
+    /// The flag or label a starred message carries.
+    pub fn flag(self) -> &'static str {
+        match self {
+            StarMethod::GmailLabel => "\\Starred",
+            StarMethod::Flagged => "\\Flagged",
+        }
+    }
+
+    /// `UID STORE` item that stars a message.
+    pub fn store_query(self) -> &'static str {
+        match self {
+            StarMethod::GmailLabel => "+X-GM-LABELS (\\Starred)",
+            StarMethod::Flagged => "+FLAGS (\\Flagged)",
+        }
+    }
+
+    /// Reads back the labels (Gmail) or flags of message `uid`, to check
+    /// for `flag()`. The imap crate can't parse X-GM-LABELS, so Gmail labels
+    /// come from `uid_fetch_labels` rather than a plain FETCH.
+    pub fn read_back<C: ImapOps>(self, client: &mut C, uid: u32) -> Result<Vec<String>> {
+        match self {
+            StarMethod::GmailLabel => Ok(client
+                .uid_fetch_labels(&uid.to_string())?
+                .remove(&uid)
+                .unwrap_or_default()),
+            StarMethod::Flagged => Ok(client
+                .uid_fetch(&uid.to_string(), "FLAGS")?
+                .into_iter()
+                .flat_map(|fetch| fetch.flags)
+                .collect()),
+        }
+    }
+
+    /// `UID SEARCH` criteria for already-starred messages.
+    pub fn search_query(self) -> &'static str {
+        match self {
+            StarMethod::GmailLabel => "X-GM-LABELS \\Starred",
+            StarMethod::Flagged => "FLAGGED",
+        }
+    }
+}
+
+/// How many times a run will re-establish a dropped session.
+pub const MAX_RECONNECTS: usize = 3;
+
+/// True when `err` means the server or network dropped the session, as
+/// opposed to the server rejecting a command.
+pub fn is_disconnect(err: &FilterError) -> bool {
+    match err {
+        FilterError::Imap(imap::Error::ConnectionLost) => true,
+        FilterError::Imap(imap::Error::Io(e)) => matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::NotConnected
+        ),
+        _ => false,
+    }
+}
+
+/// Re-establishes a dropped session (connect, log in, reselect the mailbox),
+/// at most `remaining` times per run.
+pub struct Reconnector<C> {
+    connect: Box<dyn FnMut() -> Result<C>>,
+    remaining: usize,
+}
+
+impl<C> fmt::Debug for Reconnector<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reconnector")
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+impl<C: ImapOps> Reconnector<C> {
+    pub fn new(max_reconnects: usize, connect: impl FnMut() -> Result<C> + 'static) -> Self {
+        Self {
+            connect: Box::new(connect),
+            remaining: max_reconnects,
+        }
+    }
+
+    /// Runs `op`; if it fails because the session dropped, reconnects into
+    /// `client`, selects `mailbox` again, and retries it once.
+    fn retry<T>(
+        reconnector: &mut Option<Self>,
+        client: &mut C,
+        mailbox: &str,
+        mut op: impl FnMut(&mut C) -> Result<T>,
+    ) -> Result<T> {
+        match op(client) {
+            Err(err) if is_disconnect(&err) => {
+                Self::reconnect(reconnector, client, mailbox, err)?;
+                op(client)
+            }
+            result => result,
+        }
+    }
+
+    /// Like `retry`, but after reconnecting runs `resume` instead of `op`,
+    /// for operations that may have partly taken effect before the drop.
+    fn retry_resuming<T>(
+        reconnector: &mut Option<Self>,
+        client: &mut C,
+        mailbox: &str,
+        op: impl FnOnce(&mut C) -> Result<T>,
+        resume: impl FnOnce(&mut C) -> Result<T>,
+    ) -> Result<T> {
+        match op(client) {
+            Err(err) if is_disconnect(&err) => {
+                Self::reconnect(reconnector, client, mailbox, err)?;
+                resume(client)
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces `client` with a fresh session on `mailbox`, or hands back
+    /// `err` when reconnecting is off or its attempts are used up.
+    fn reconnect(
+        reconnector: &mut Option<Self>,
+        client: &mut C,
+        mailbox: &str,
+        err: FilterError,
+    ) -> Result<()> {
+        let Some(reconnector) = reconnector
+            .as_mut()
+            .filter(|reconnector| reconnector.remaining > 0)
+        else {
+            return Err(err);
+        };
+
+        reconnector.remaining -= 1;
+        warn!(
+            "IMAP session dropped ({}); reconnecting ({} attempt(s) left)",
+            err, reconnector.remaining
+        );
+        let mut fresh = (reconnector.connect)()?;
+        fresh.select(mailbox)?;
+        *client = fresh;
+        Ok(())
+    }
+}
+
+/// Sleeps between mutating IMAP calls to stay under provider rate limits.
+pub struct Throttle {
+    delay: Duration,
+    sleep: Box<dyn FnMut(Duration)>,
+    calls: usize,
+}
+
+impl fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Throttle")
+            .field("delay", &self.delay)
+            .field("calls", &self.calls)
+            .finish()
+    }
+}
+
+impl Throttle {
+    pub fn new(delay: Duration, sleep: impl FnMut(Duration) + 'static) -> Self {
+        Self {
+            delay,
+            sleep: Box::new(sleep),
+            calls: 0,
+        }
+    }
+
+    /// Call before each mutating command; waits `delay` unless it's the first.
+    fn pause(&mut self) {
+        if !self.delay.is_zero() && self.calls > 0 {
+            (self.sleep)(self.delay);
+        }
+        self.calls += 1;
+    }
+}
 
 #[derive(Debug)]
-pub struct IMAPFilter {
-    client: Session<TlsStream<TcpStream>>,
+pub struct IMAPFilter<C: ImapOps = Session<ImapStream>> {
+    client: C,
     filters: Vec<MessageFilter>,
+    options: FilterOptions,
+    capabilities: HashSet<String>,
+    move_method: MoveMethod,
+    star_method: StarMethod,
+    reconnector: Option<Reconnector<C>>,
+    /// Set (e.g. by a Ctrl-C handler) to stop before the next message.
+    stop: Arc<AtomicBool>,
+    /// When `execute` started, for the `max_runtime` check.
+    started: Instant,
+    audit: Option<AuditLog>,
+    /// Per-filter action counts from the previous run, for spike warnings.
+    previous_counts: Option<BTreeMap<String, usize>>,
+    throttle: Throttle,
+}
+
+/// Connects and authenticates a new session.
+fn login(
+    creds: &Credentials,
+    settings: &ConnectionSettings,
+    protocol_debug: bool,
+) -> Result<Session<ImapStream>> {
+    debug!(
+        "Initializing IMAP connection to {} ({:?})",
+        creds.domain, settings
+    );
+
+    // The login error carries the client back; drop it so credentials never reach the log
+    let mut client = connection::connect(&creds.domain, settings)?
+        .login(&creds.username, &creds.password)
+        .map_err(|(e, _)| FilterError::Auth(e.to_string()))?;
+
+    // Only enabled after LOGIN so the credentials exchange is never echoed
+    client.debug = protocol_debug;
+
+    debug!("Successfully connected and authenticated to IMAP server.");
+    Ok(client)
 }
 
 impl IMAPFilter {
-    pub fn new(domain: String, username: String, password: String, filters: Vec<MessageFilter>) -> Result<Self> {
-        debug!("Initializing IMAP connection to {}", domain);
+    pub fn new(
+        creds: Credentials,
+        filters: Vec<MessageFilter>,
+        options: FilterOptions,
+        settings: ConnectionSettings,
+        protocol_debug: bool,
+    ) -> Result<Self> {
+        let client = login(&creds, &settings, protocol_debug)?;
+        let reconnector = Reconnector::new(MAX_RECONNECTS, move || {
+            login(&creds, &settings, protocol_debug)
+        });
+        Ok(Self::with_client(client, filters, options).with_reconnector(reconnector))
+    }
+}
+
+impl<C: ImapOps> IMAPFilter<C> {
+    /// Wraps an already-authenticated client.
+    pub fn with_client(client: C, filters: Vec<MessageFilter>, options: FilterOptions) -> Self {
+        Self {
+            client,
+            filters,
+            capabilities: HashSet::new(),
+            move_method: MoveMethod::default(),
+            star_method: StarMethod::default(),
+            reconnector: None,
+            stop: Arc::default(),
+            started: Instant::now(),
+            audit: None,
+            previous_counts: None,
+            throttle: Throttle::new(options.throttle, std::thread::sleep),
+            options,
+        }
+    }
+
+    /// Replaces how the throttle waits, e.g. to count sleeps in tests.
+    pub fn with_sleep(mut self, sleep: impl FnMut(Duration) + 'static) -> Self {
+        self.throttle = Throttle::new(self.options.throttle, sleep);
+        self
+    }
+
+    /// Shares a flag that, once set, makes the run finish the message in
+    /// hand, skip the rest, and log out.
+    pub fn with_stop_flag(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Appends every action to `audit` as well as the run's summary.
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Warns at the end of the run about filters that acted far more often
+    /// than they did in `previous`.
+    pub fn with_previous_counts(mut self, previous: BTreeMap<String, usize>) -> Self {
+        self.previous_counts = Some(previous);
+        self
+    }
+
+    /// Lets the run recover from a dropped session by reconnecting.
+    pub fn with_reconnector(mut self, reconnector: Reconnector<C>) -> Self {
+        self.reconnector = Some(reconnector);
+        self
+    }
+
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    /// Rewrites Move targets and source mailboxes written with `/` (e.g.
+    /// `Archive/2024`) to use the server's hierarchy delimiter, as reported
+    /// by `LIST "" ""`.
+    fn translate_move_targets(&mut self) -> Result<()> {
+        if !self
+            .filters
+            .iter()
+            .flat_map(|filter| {
+                filter
+                    .move_to
+                    .iter()
+                    .map(|target| &target.folder)
+                    .chain(&filter.mailbox)
+                    .chain(&filter.append_to)
+            })
+            .any(|path| path.contains('/'))
+        {
+            return Ok(());
+        }
+
+        let delimiter = self
+            .client
+            .list(Some(""), Some(""))?
+            .into_iter()
+            .find_map(|mailbox| mailbox.delimiter);
+        let Some(delimiter) = delimiter else {
+            warn!("Server did not report a hierarchy delimiter; using Move targets as written");
+            return Ok(());
+        };
+        debug!("Server hierarchy delimiter: {:?}", delimiter);
+
+        for filter in &mut self.filters {
+            let folder = filter.move_to.as_mut().map(|target| &mut target.folder);
+            for path in [folder, filter.mailbox.as_mut(), filter.append_to.as_mut()]
+                .into_iter()
+                .flatten()
+            {
+                *path = translate_folder_path(path, &delimiter);
+            }
+        }
+        Ok(())
+    }
 
-        let tls = TlsConnector::builder().build()?;
-        let client = imap::connect((domain.as_str(), 993), &domain, &tls)
-            .map_err(|e| eyre!("IMAP connection failed: {:?}", e))?
-            .login(username, password)
-            .map_err(|e| eyre!("IMAP authentication failed: {:?}", e))?;
+    /// Checks every folder the filters use against one LIST, up front, so
+    /// typos are reported together instead of once per message. Missing Move
+    /// and Append targets are created (unless disabled); a missing mailbox to
+    /// read from is an error.
+    fn ensure_move_targets(&mut self) -> Result<()> {
+        let targets: BTreeSet<&str> = self
+            .filters
+            .iter()
+            .flat_map(|filter| {
+                filter
+                    .move_to
+                    .iter()
+                    .map(|target| target.folder.as_str())
+                    .chain(filter.append_to.as_deref())
+            })
+            .collect();
+        let sources: BTreeSet<&str> = self.filters.iter().map(|filter| filter.mailbox()).collect();
+        if targets.is_empty()
+            && sources
+                .iter()
+                .all(|source| source.eq_ignore_ascii_case(DEFAULT_MAILBOX))
+        {
+            return Ok(());
+        }
+
+        let existing: HashSet<String> = self
+            .client
+            .list(Some(""), Some("*"))?
+            .into_iter()
+            .map(|mailbox| mailbox.name)
+            .collect();
+        let missing_sources = missing_folders(sources, &existing);
+        if !missing_sources.is_empty() {
+            return Err(FilterError::Config(format!(
+                "Mailbox(es) to read from do not exist: {:?}",
+                missing_sources
+            )));
+        }
+
+        let missing_targets = missing_folders(targets, &existing);
+        if missing_targets.is_empty() {
+            return Ok(());
+        }
+        if !self.options.create_folders {
+            warn!(
+                "Move/Append target(s) do not exist and folder creation is disabled: {:?}",
+                missing_targets
+            );
+            return Ok(());
+        }
+        for target in missing_targets {
+            info!("Creating missing folder '{}'", target);
+            self.client.create(&target)?;
+        }
+
+        Ok(())
+    }
 
-        debug!("Successfully connected and authenticated to IMAP server.");
-        Ok(Self { client, filters })
+    /// Mailboxes the filters read from, each once, in order of first use.
+    fn mailboxes(&self) -> Vec<String> {
+        let mut mailboxes: Vec<String> = Vec::new();
+        for filter in &self.filters {
+            if !mailboxes.iter().any(|mailbox| mailbox == filter.mailbox()) {
+                mailboxes.push(filter.mailbox().to_string());
+            }
+        }
+        if mailboxes.is_empty() {
+            mailboxes.push(DEFAULT_MAILBOX.to_string());
+        }
+        mailboxes
     }
 
-    fn fetch_messages(&mut self) -> Result<Vec<Message>> {
-        debug!("Fetching messages from INBOX");
+    /// Fetches at most `limit` messages from `mailbox`.
+    fn fetch_messages(&mut self, mailbox: &str, limit: Option<usize>) -> Result<Vec<Message>> {
+        debug!("Fetching messages from {}", mailbox);
+
+        let mailbox_status = self.client.select(mailbox)?;
+        debug!("Mailbox selection status: {:?}", mailbox_status);
+
+        let query = build_search_query(
+            &self.options.inbox_query,
+            self.options.gmail_search.as_deref(),
+            self.options.references.as_deref(),
+            self.options.since,
+        );
+        validate_imap_query(&query)?;
+        let messages = self.client.search(&query)?;
+        debug!(
+            "Found {} messages in {} matching {}",
+            messages.len(),
+            mailbox,
+            query
+        );
 
-        let inbox_status = self.client.select("INBOX")?;
-        debug!("Mailbox selection status: {:?}", inbox_status);
+        let found = messages.len();
+        let messages = limit_ids(messages, limit);
+        if messages.len() < found {
+            info!(
+                "Limit applied: processing {} of {} messages",
+                messages.len(),
+                found
+            );
+        }
 
-        let messages = self.client.search("ALL")?;
-        debug!("Found {} messages in INBOX", messages.len());
+        let mut fetches = Vec::with_capacity(messages.len());
+        for sequence_set in sequence_sets(&messages, self.options.fetch_chunk_size) {
+            fetches.extend(self.client.fetch(&sequence_set, FETCH_ITEMS)?);
+        }
+        let returned: HashSet<u32> = fetches.iter().map(|fetch| fetch.seq).collect();
+        let vanished: Vec<u32> = messages
+            .iter()
+            .copied()
+            .filter(|seq| !returned.contains(seq))
+            .collect();
+        if !vanished.is_empty() {
+            warn!(
+                "{} message(s) disappeared between SEARCH and FETCH; skipping {:?}",
+                vanished.len(),
+                vanished
+            );
+        }
 
-        let fetches = self.client.fetch(
-            messages.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(","),
-            "RFC822"
-        )?;
+        let (mut results, skipped) = build_messages(fetches, self.options.progress_interval);
+        if !skipped.is_empty() {
+            warn!(
+                "Skipped {} message(s) the server returned incomplete data for (sequence numbers): {:?}",
+                skipped.len(),
+                skipped
+            );
+        }
 
-        let mut results = Vec::new();
-        for fetch in fetches.iter() {
-            if let Some(body) = fetch.body() {
-                results.push(Message::new(fetch.message, body.to_vec()));
+        if self.filters.iter().any(|filter| filter.apply_to_thread) && !results.is_empty() {
+            let uids: Vec<u32> = results.iter().map(|msg| msg.uid).collect();
+            let mut thread_ids = HashMap::new();
+            for uid_set in sequence_sets(&uids, self.options.fetch_chunk_size) {
+                thread_ids.extend(self.client.uid_fetch_thread_ids(&uid_set)?);
+            }
+            for msg in &mut results {
+                msg.thread_id = thread_ids.get(&msg.uid).copied();
             }
         }
 
@@ -54,67 +875,2800 @@ impl IMAPFilter {
         Ok(results)
     }
 
-    fn apply_filters(&mut self, mut messages: Vec<Message>) {
-        info!("Applying filters to {} messages", messages.len());
+    /// Asks the server what it supports and picks the Move implementation.
+    fn detect_capabilities(&mut self) -> Result<()> {
+        self.capabilities = self.client.capabilities()?;
+        self.move_method = MoveMethod::select(&self.capabilities);
+        self.star_method = StarMethod::select(&self.capabilities);
+        debug!(
+            "Server capabilities: {:?}; moving with {:?}, starring with {:?}",
+            self.capabilities, self.move_method, self.star_method
+        );
 
-        for filter in &self.filters {
+        if !self.capabilities.contains(GMAIL_CAPABILITY) {
+            if self.options.gmail_search.is_some() {
+                return Err(FilterError::Unsupported(format!(
+                    "gmail_search needs Gmail's X-GM-RAW, but the server does not advertise {}",
+                    GMAIL_CAPABILITY
+                )));
+            }
+            if let Some(filter) = self.filters.iter().find(|filter| filter.apply_to_thread) {
+                return Err(FilterError::Unsupported(format!(
+                    "Filter '{}' uses apply_to_thread, which needs Gmail's X-GM-THRID, but the server does not advertise {}",
+                    filter.name,
+                    GMAIL_CAPABILITY
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// UIDs in the selected mailbox that are already starred, so starring them again
+    /// can be skipped. A failed search just means nothing is skipped.
+    fn starred_uids(&mut self) -> HashSet<u32> {
+        match self.client.uid_search(self.star_method.search_query()) {
+            Ok(uids) => uids,
+            Err(e) => {
+                warn!("Could not look up already-starred messages: {}", e);
+                HashSet::new()
+            }
+        }
+    }
+
+    /// True when the message's sender is covered by `never_touch`.
+    fn is_protected(&self, msg: &Message) -> bool {
+        let senders: Vec<String> = msg.from.iter().map(|(_, email)| email.clone()).collect();
+        self.options.never_touch.matches(&senders)
+    }
+
+    /// Applies the actions of each filter reading from `mailbox`, which must
+    /// be selected. A failed action is logged and the run continues, unless
+    /// `fail_fast` is set.
+    fn apply_filters(&mut self, mailbox: &str, mut messages: Vec<Message>) -> Result<Summary> {
+        if self.options.dedupe_by_message_id {
+            let (unique, duplicates) = dedupe_by_message_id(messages);
+            if !duplicates.is_empty() {
+                info!(
+                    "Skipping {} duplicate message(s) by Message-ID: {:?}",
+                    duplicates.len(),
+                    duplicates
+                );
+            }
+            messages = unique;
+        }
+
+        info!(
+            "Applying filters to {} messages in {} ({:?})",
+            messages.len(),
+            mailbox,
+            self.options.strategy
+        );
+        if let Some(msg) = self
+            .options
+            .debug_uid
+            .and_then(|uid| messages.iter().find(|msg| msg.uid == uid))
+        {
+            let mut headers: Vec<_> = msg.headers.iter().collect();
+            headers.sort();
+            info!("[debug-uid {}] Headers: {:?}", msg.uid, headers);
+            info!("[debug-uid {}] Parsed: {:?}", msg.uid, msg);
+            for decision in trace_message(
+                msg,
+                self.filters
+                    .iter()
+                    .filter(|filter| filter.mailbox() == mailbox),
+                &self.options,
+                Utc::now(),
+            ) {
+                info!("[debug-uid {}] {:?}", msg.uid, decision);
+            }
+        }
+        let mut summary = Summary::default();
+        let mut starred = if self
+            .filters
+            .iter()
+            .any(|filter| filter.mailbox() == mailbox && filter.star.unwrap_or(false))
+        {
+            self.starred_uids()
+        } else {
+            HashSet::new()
+        };
+        // UIDs a real move has taken out of `mailbox`; under AllMatch later
+        // filters still list them, but a UID command on them would silently
+        // do nothing and be counted as applied
+        let mut moved_away = HashSet::new();
+        let style = self.options.log_style;
+
+        let mut last_uid = None;
+        let progress_interval = self.options.progress_interval;
+        let client = &mut self.client;
+        let matches = match_filters(
+            self.filters
+                .iter()
+                .filter(|filter| filter.mailbox() == mailbox),
+            messages,
+            &self.options,
+            |filter, matched, exclude| {
+                expand_threads(
+                    client,
+                    matched,
+                    exclude,
+                    &scope("filter", &filter.name),
+                    progress_interval,
+                )
+            },
+        );
+        'filters: for (filter, matched_messages) in matches {
             filter.print_details();
+            let tag = scope("filter", &filter.name);
+            summary.matched += matched_messages.len();
+            let mut counts = FilterCounts {
+                name: filter.name.clone(),
+                matched: matched_messages.len(),
+                ..Default::default()
+            };
+            if let Some(max) = filter
+                .max_affected
+                .filter(|&max| matched_messages.len() > max)
+            {
+                error!(
+                    "{} {} Would act on {} messages, over max_affected {}; skipping this filter",
+                    tag,
+                    style.marker(Marker::Failure),
+                    matched_messages.len(),
+                    max
+                );
+                summary.errors += 1;
+                counts.failed += 1;
+                summary.filters.push(counts);
+                continue;
+            }
 
-            let (matched_messages, remaining_messages): (Vec<_>, Vec<_>) = messages
-                .into_iter()
-                .partition(|msg| {
-                    let (from_match, to_match, cc_match) = msg.compare(filter);
-                    from_match && to_match && cc_match
-                });
-
-            for msg in &matched_messages {
-                info!("Processing UID: {} | Subject: {}", msg.uid, msg.subject);
-
-                // Moving message by applying a Gmail label instead of using `uid_mv`
-                if let Some(destination) = &filter.move_to {
-                    info!("Applying label '{}' to email UID {}", destination, msg.uid);
-                    if let Err(e) = self.client.uid_store(msg.uid.to_string(), &format!("+X-GM-LABELS \"{}\"", destination)) {
-                        error!("Failed to apply label '{}' to email UID {}: {:?} | Subject: {}", destination, msg.uid, e, msg.subject);
-                    } else {
-                        info!("✅ Successfully labeled UID {} with '{}' | Subject: {}", msg.uid, destination, msg.subject);
-                    }
+            for (index, msg) in matched_messages.iter().enumerate() {
+                if runtime_exceeded(self.started, self.options.max_runtime, Instant::now()) {
+                    warn!(
+                        "Max runtime reached; stopping before UID {} (last processed: {:?})",
+                        msg.uid, last_uid
+                    );
+                    summary.timed_out = true;
+                    summary.interrupted = true;
+                    summary.filters.push(counts);
+                    break 'filters;
+                }
+                if self.stop.load(Ordering::SeqCst) {
+                    warn!(
+                        "Interrupted; stopping before UID {} (last processed: {:?})",
+                        msg.uid, last_uid
+                    );
+                    summary.interrupted = true;
+                    summary.filters.push(counts);
+                    break 'filters;
+                }
+                last_uid = Some(msg.uid);
+                if should_log_progress(
+                    index + 1,
+                    matched_messages.len(),
+                    self.options.progress_interval,
+                ) {
+                    info!(
+                        "{} Processing {}/{} matched messages",
+                        tag,
+                        index + 1,
+                        matched_messages.len()
+                    );
+                }
+                if moved_away.contains(&msg.uid) {
+                    info!(
+                        "{} Skipping UID {}: an earlier filter moved it out of {} | Subject: {}",
+                        tag, msg.uid, mailbox, msg.subject
+                    );
+                    continue;
+                }
+                if self.is_protected(msg) {
+                    info!(
+                        "{} Skipping UID {} from protected sender {:?} | Subject: {}",
+                        tag,
+                        msg.uid,
+                        msg.sender_key(),
+                        msg.subject
+                    );
+                    continue;
                 }
+                info!(
+                    "{} Processing UID: {} | Date: {} | Subject: {}",
+                    tag,
+                    msg.uid,
+                    msg.sent_date(),
+                    msg.subject
+                );
 
-                // Starring the email using Gmail-friendly X-GM-LABELS
-                if filter.star.unwrap_or(false) {
-                    info!("Starring email UID: {} | Subject: {}", msg.uid, msg.subject);
-                    if let Err(e) = self.client.uid_store(msg.uid.to_string(), "+X-GM-LABELS (\\Starred)") {
-                        error!("Failed to star email UID {}: {:?} | Subject: {}", msg.uid, e, msg.subject);
+                // Star first so the flag travels with a copied or moved message
+                if filter.star.unwrap_or(false) && starred.contains(&msg.uid) {
+                    info!(
+                        "{} UID {} already starred — skipping | Subject: {}",
+                        tag, msg.uid, msg.subject
+                    );
+                    record_action(&mut self.audit, &mut summary, msg, "star", "skipped");
+                } else if filter.star.unwrap_or(false) {
+                    info!(
+                        "{} Starring email UID: {} | Subject: {}",
+                        tag, msg.uid, msg.subject
+                    );
+                    self.throttle.pause();
+                    let method = self.star_method;
+                    if let Err(e) = Reconnector::retry(
+                        &mut self.reconnector,
+                        &mut self.client,
+                        mailbox,
+                        |client| client.uid_store(&msg.uid.to_string(), method.store_query()),
+                    ) {
+                        error!(
+                            "{} Failed to star email UID {}: {:?} | Subject: {}",
+                            tag, msg.uid, e, msg.subject
+                        );
+                        summary.errors += 1;
+                        counts.failed += 1;
+                        record_action(&mut self.audit, &mut summary, msg, "star", "failed");
+                        if self.options.fail_fast {
+                            return Err(FilterError::Action {
+                                message: format!("Failed to star UID {}", msg.uid),
+                                source: Box::new(e),
+                            });
+                        }
                     } else {
-                        info!("⭐ Successfully starred UID {} using Gmail's X-GM-LABELS | Subject: {}", msg.uid, msg.subject);
+                        summary.applied += 1;
+                        counts.starred += 1;
+                        record_action(&mut self.audit, &mut summary, msg, "star", "ok");
+                        starred.insert(msg.uid);
+                        info!(
+                            "{} {} Successfully starred UID {} with {:?} | Subject: {}",
+                            tag,
+                            style.marker(Marker::Star),
+                            msg.uid,
+                            method,
+                            msg.subject
+                        );
 
-                        // Fetch and log the updated labels for verification
-                        if let Ok(updated_labels) = self.client.uid_fetch(msg.uid.to_string(), "X-GM-LABELS") {
-                            debug!("Updated LABELS for UID {}: {:?}", msg.uid, updated_labels);
+                        // Fetch and log the updated flags for verification
+                        if let Ok(updated) = method.read_back(&mut self.client, msg.uid) {
+                            debug!("{} Updated flags for UID {}: {:?}", tag, msg.uid, updated);
 
-                            if !updated_labels.iter().any(|fetch| fetch.flags().contains(&imap::types::Flag::Custom("\\Starred".to_string().into()))) {
-                                error!("❌ FAILURE: Email UID {} does NOT have \\Starred after operation! | Subject: {}", msg.uid, msg.subject);
+                            if !updated.iter().any(|flag| flag == method.flag()) {
+                                error!("{} {} FAILURE: Email UID {} does NOT have {} after operation! | Subject: {}", tag, style.marker(Marker::Failure), msg.uid, method.flag(), msg.subject);
                             }
                         }
                     }
                 }
-            }
 
-            messages = remaining_messages; // Continue filtering only the remaining messages
+                // Append before any move, while the UID still names the message here
+                if let Some(destination) = &filter.append_to {
+                    info!(
+                        "{} Appending a copy of UID {} to '{}'",
+                        tag, msg.uid, destination
+                    );
+                    self.throttle.pause();
+                    if let Err(e) = Reconnector::retry(
+                        &mut self.reconnector,
+                        &mut self.client,
+                        mailbox,
+                        |client| append_copy(client, msg.uid, destination),
+                    ) {
+                        error!(
+                            "{} Failed to append UID {} to '{}': {:?} | Subject: {}",
+                            tag, msg.uid, destination, e, msg.subject
+                        );
+                        summary.errors += 1;
+                        counts.failed += 1;
+                        record_action(
+                            &mut self.audit,
+                            &mut summary,
+                            msg,
+                            format!("append:{}", destination),
+                            "failed",
+                        );
+                        if self.options.fail_fast {
+                            return Err(FilterError::Action {
+                                message: format!(
+                                    "Failed to append UID {} to '{}'",
+                                    msg.uid, destination
+                                ),
+                                source: Box::new(e),
+                            });
+                        }
+                    } else {
+                        summary.applied += 1;
+                        counts.appended += 1;
+                        record_action(
+                            &mut self.audit,
+                            &mut summary,
+                            msg,
+                            format!("append:{}", destination),
+                            "ok",
+                        );
+                        info!(
+                            "{} Appended a copy of UID {} to '{}' | Subject: {}",
+                            tag, msg.uid, destination, msg.subject
+                        );
+                    }
+                }
+
+                if let Some(target) = &filter.move_to {
+                    let (doing, verb, done) = if target.keep_in_inbox {
+                        ("Copying", "copy", "copied")
+                    } else {
+                        ("Moving", "move", "moved")
+                    };
+                    let destination = &target.folder;
+                    info!(
+                        "{} {} email UID {} to '{}' ({:?})",
+                        tag, doing, msg.uid, destination, self.move_method
+                    );
+                    let method = self.move_method;
+                    self.throttle.pause();
+                    let result = if target.keep_in_inbox {
+                        Reconnector::retry(
+                            &mut self.reconnector,
+                            &mut self.client,
+                            mailbox,
+                            |client| method.apply_keeping_source(client, msg.uid, destination),
+                        )
+                    } else {
+                        Reconnector::retry_resuming(
+                            &mut self.reconnector,
+                            &mut self.client,
+                            mailbox,
+                            |client| method.apply(client, msg.uid, destination),
+                            |client| method.resume(client, msg.uid, destination),
+                        )
+                    };
+                    if let Err(e) = result {
+                        error!(
+                            "{} Failed to {} email UID {} to '{}': {:?} | Subject: {}",
+                            tag, verb, msg.uid, destination, e, msg.subject
+                        );
+                        summary.errors += 1;
+                        counts.failed += 1;
+                        record_action(
+                            &mut self.audit,
+                            &mut summary,
+                            msg,
+                            format!("{}:{}", verb, destination),
+                            "failed",
+                        );
+                        if self.options.fail_fast {
+                            return Err(FilterError::Action {
+                                message: format!(
+                                    "Failed to {} UID {} to '{}'",
+                                    verb, msg.uid, destination
+                                ),
+                                source: Box::new(e),
+                            });
+                        }
+                    } else {
+                        if !target.keep_in_inbox && method.removes_from_source() {
+                            moved_away.insert(msg.uid);
+                        }
+                        summary.applied += 1;
+                        counts.moved += 1;
+                        record_action(
+                            &mut self.audit,
+                            &mut summary,
+                            msg,
+                            format!("{}:{}", verb, destination),
+                            "ok",
+                        );
+                        info!(
+                            "{} {} Successfully {} UID {} to '{}' | Subject: {}",
+                            tag,
+                            style.marker(Marker::Move),
+                            done,
+                            msg.uid,
+                            destination,
+                            msg.subject
+                        );
+                    }
+                }
+            }
+            summary.filters.push(counts);
         }
 
         info!("Finished applying filters.");
+        if !summary.filters.is_empty() {
+            info!(
+                "{}",
+                summary
+                    .filters
+                    .iter()
+                    .map(FilterCounts::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            );
+        }
+        Ok(summary)
     }
 
-    pub fn execute(&mut self) -> Result<()> {
+    /// Selects INBOX and logs out, returning its message count. Runs no
+    /// filters, so it only proves the server is reachable and the login works.
+    pub fn count_inbox(&mut self) -> Result<u32> {
+        let inbox = self.client.select("INBOX")?;
+        self.client.logout()?;
+        Ok(inbox.exists)
+    }
+
+    pub fn execute(&mut self) -> Result<Summary> {
         debug!("Executing IMAP filter process");
+        self.started = Instant::now();
 
-        let messages = self.fetch_messages()?;
-        self.apply_filters(messages);
+        self.detect_capabilities()?;
+        self.translate_move_targets()?;
+        self.ensure_move_targets()?;
+        let summary = match self.filter_mailboxes() {
+            Ok(summary) => summary,
+            Err(e) => {
+                // Still close the session cleanly; the original error is what gets reported
+                if let Err(logout_error) = self.client.logout() {
+                    warn!("Logout after failure also failed: {}", logout_error);
+                }
+                return Err(e);
+            }
+        };
 
         self.client.logout()?;
         debug!("IMAP session logged out successfully.");
 
-        Ok(())
+        if let Some(previous) = &self.previous_counts {
+            for spike in action_spikes(
+                previous,
+                &summary.action_counts(),
+                self.options.spike_factor,
+            ) {
+                warn!(
+                    "Filter '{}' took {} action(s), up from {} last run; check its conditions",
+                    spike.filter, spike.current, spike.previous
+                );
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Fetches and filters each mailbox in turn, selecting each one once.
+    fn filter_mailboxes(&mut self) -> Result<Summary> {
+        let mut summary = Summary::default();
+        // `limit` caps the whole run, not each mailbox
+        let mut remaining = self.options.limit;
+        for mailbox in self.mailboxes() {
+            if remaining == Some(0) {
+                info!("Limit reached; not reading {}", mailbox);
+                break;
+            }
+            let messages = match self.fetch_messages(&mailbox, remaining) {
+                Err(err) if is_disconnect(&err) => {
+                    Reconnector::reconnect(&mut self.reconnector, &mut self.client, &mailbox, err)?;
+                    self.fetch_messages(&mailbox, remaining)?
+                }
+                result => result?,
+            };
+            let fetched = messages.len();
+            remaining = remaining.map(|left| left.saturating_sub(fetched));
+            summary.absorb(Summary {
+                fetched,
+                ..self.apply_filters(&mailbox, messages)?
+            });
+            if summary.interrupted {
+                break;
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// What's fetched to re-APPEND a message elsewhere.
+pub const APPEND_FETCH_ITEMS: &str = "(FLAGS INTERNALDATE BODY.PEEK[])";
+
+/// APPENDs a copy of message `uid` from the selected mailbox to `mailbox`,
+/// keeping its flags (less `\Recent`, which servers set themselves) and
+/// INTERNALDATE.
+pub fn append_copy<C: ImapOps>(client: &mut C, uid: u32, mailbox: &str) -> Result<()> {
+    let fetched = client
+        .uid_fetch(&uid.to_string(), APPEND_FETCH_ITEMS)?
+        .into_iter()
+        .next();
+    let Some(FetchedMessage {
+        body: Some(body),
+        flags,
+        internal_date,
+        ..
+    }) = fetched
+    else {
+        return Err(FilterError::Imap(imap::Error::Bad(format!(
+            "UID {} returned no message body to append",
+            uid
+        ))));
+    };
+    let flags: Vec<String> = flags
+        .into_iter()
+        .filter(|flag| flag != "\\Recent")
+        .collect();
+    client.append(mailbox, &body, &flags, internal_date)
+}
+
+/// Splits ids into comma-separated sets of at most `chunk_size` ids each,
+/// so no single command line grows with the mailbox.
+pub fn sequence_sets(ids: &[u32], chunk_size: usize) -> Vec<String> {
+    ids.chunks(chunk_size.max(1))
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .collect()
+}
+
+/// Converts a `/`-separated folder path to the server's hierarchy delimiter.
+pub fn translate_folder_path(path: &str, delimiter: &str) -> String {
+    path.split('/').collect::<Vec<_>>().join(delimiter)
+}
+
+/// Sorts message ids ascending and keeps at most `limit` of them.
+pub fn limit_ids(ids: HashSet<u32>, limit: Option<usize>) -> Vec<u32> {
+    let mut ids: Vec<u32> = ids.into_iter().collect();
+    ids.sort_unstable();
+    if let Some(limit) = limit {
+        ids.truncate(limit);
+    }
+    ids
+}
+
+/// The `referenced` folders that aren't in `existing`, in order. INBOX
+/// always exists, whatever its case, even when LIST leaves it out.
+pub fn missing_folders<'a>(
+    referenced: impl IntoIterator<Item = &'a str>,
+    existing: &HashSet<String>,
+) -> Vec<String> {
+    referenced
+        .into_iter()
+        .filter(|folder| {
+            !folder.eq_ignore_ascii_case(DEFAULT_MAILBOX) && !existing.contains(*folder)
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Keeps an action in the run's summary and, when configured, the audit log.
+/// A failed audit write is logged rather than stopping the run.
+fn record_action(
+    audit: &mut Option<AuditLog>,
+    summary: &mut Summary,
+    msg: &Message,
+    action: impl Into<String>,
+    result: &str,
+) {
+    let record = ActionRecord::new(msg, action, result);
+    if let Some(audit) = audit {
+        if let Err(e) = audit.write(&AuditEntry::new(msg, &record.action, result, Utc::now())) {
+            error!("Failed to write audit log entry for UID {}: {}", msg.uid, e);
+        }
+    }
+    summary.actions.push(record);
+}
+
+/// True once a run that began at `started` has used up its `budget`; never
+/// without one.
+pub fn runtime_exceeded(started: Instant, budget: Option<Duration>, now: Instant) -> bool {
+    budget.is_some_and(|budget| now.saturating_duration_since(started) >= budget)
+}
+
+/// True on every `interval`th item and on the last one, so long loops report
+/// progress without flooding the log. An interval of 0 never logs.
+pub fn should_log_progress(processed: usize, total: usize, interval: usize) -> bool {
+    interval > 0 && processed > 0 && (processed.is_multiple_of(interval) || processed == total)
+}
+
+/// Builds messages from fetch results, keyed by UID. A message without a
+/// UID or a body is logged and skipped rather than aborting the run (a
+/// sequence number is never a safe stand-in for a UID); its sequence number
+/// is returned in the second element so the caller can report it.
+pub fn build_messages(
+    fetched: Vec<FetchedMessage>,
+    progress_interval: usize,
+) -> (Vec<Message>, Vec<u32>) {
+    let mut messages = Vec::new();
+    let mut skipped = Vec::new();
+    let total = fetched.len();
+
+    for (index, fetch) in fetched.into_iter().enumerate() {
+        if should_log_progress(index + 1, total, progress_interval) {
+            info!("Processed {}/{} messages", index + 1, total);
+        }
+
+        let Some(id) = fetch.uid else {
+            warn!("No UID returned for message #{}, skipping", fetch.seq);
+            skipped.push(fetch.seq);
+            continue;
+        };
+        let Some(body) = fetch.body else {
+            warn!("No body returned for message {}, skipping", id);
+            skipped.push(fetch.seq);
+            continue;
+        };
+        if fetch.internal_date.is_none() {
+            warn!(
+                "No INTERNALDATE returned for message {}, date-based conditions will not apply",
+                id
+            );
+        }
+
+        let body_len = body.len() as u64;
+        let mut message = Message::new(id, body);
+        message.seq = fetch.seq;
+        message.seen = fetch.flags.iter().any(|flag| flag == "\\Seen");
+        message.internal_date = fetch.internal_date;
+        message.thread_id = fetch.thread_id;
+        // Servers report RFC822.SIZE, which is the length of the message we fetched
+        message.size = Some(fetch.size.map_or(body_len, u64::from));
+        messages.push(message);
+    }
+
+    (messages, skipped)
+}
+
+/// Keeps the first message for each Message-ID and returns the UIDs of the
+/// later copies. Messages without a Message-ID are never considered duplicates.
+pub fn dedupe_by_message_id(messages: Vec<Message>) -> (Vec<Message>, Vec<u32>) {
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+
+    let unique = messages
+        .into_iter()
+        .filter(|msg| match &msg.message_id {
+            Some(id) if !seen.insert(id.clone()) => {
+                duplicates.push(msg.uid);
+                false
+            }
+            _ => true,
+        })
+        .collect();
+
+    (unique, duplicates)
+}
+
+/// How one filter judged one message, logged for `debug_uid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterDecision {
+    pub filter: String,
+    pub from: bool,
+    pub to: bool,
+    pub cc: bool,
+    /// Every condition, age included, matched.
+    pub matches: bool,
+}
+
+/// Each filter's verdict on `msg` at `now`, in filter order, judged in
+/// isolation: a `FirstMatch` run only acts on the first match.
+pub fn trace_message<'a>(
+    msg: &Message,
+    filters: impl IntoIterator<Item = &'a MessageFilter>,
+    options: &FilterOptions,
+    now: DateTime<Utc>,
+) -> Vec<FilterDecision> {
+    filters
+        .into_iter()
+        .map(|filter| {
+            let (from, to, cc) = msg.compare(filter);
+            FilterDecision {
+                filter: filter.name.clone(),
+                from,
+                to,
+                cc,
+                matches: msg.matches(filter) && msg.matches_age(filter, options.age_basis, now),
+            }
+        })
+        .collect()
+}
+
+/// Pairs each filter with the messages it should act on. Under `FirstMatch`
+/// a matched message is removed from the working set so later filters never
+/// see it; under `AllMatch` every filter sees every message. A `catch_all`
+/// filter only sees messages no earlier filter matched, under either.
+///
+/// For `apply_to_thread` filters, `expand_thread` is given the matched
+/// messages and the UIDs it must not add (already claimed, or kept by
+/// `keep_recent_per_sender`), and returns them with their thread siblings.
+/// The siblings are claimed like any match, so later filters skip them.
+pub fn match_filters<'a>(
+    filters: impl IntoIterator<Item = &'a MessageFilter>,
+    mut messages: Vec<Message>,
+    options: &FilterOptions,
+    mut expand_thread: impl FnMut(&MessageFilter, Vec<Message>, &HashSet<u32>) -> Vec<Message>,
+) -> Vec<(&'a MessageFilter, Vec<Message>)> {
+    let mut results = Vec::new();
+    let now = Utc::now();
+    let mut claimed = HashSet::new();
+
+    for filter in filters {
+        let is_match = |msg: &Message| {
+            !(filter.catch_all && claimed.contains(&msg.uid))
+                && msg.matches(filter)
+                && msg.matches_age(filter, options.age_basis, now)
+        };
+        let mut matched: Vec<Message> = match options.strategy {
+            FilterStrategy::FirstMatch => {
+                let (matched, remaining) = messages.into_iter().partition(|msg| is_match(msg));
+                messages = remaining; // Continue filtering only the remaining messages
+                matched
+            }
+            FilterStrategy::AllMatch => messages
+                .iter()
+                .filter(|msg| is_match(msg))
+                .cloned()
+                .collect(),
+        };
+        claimed.extend(matched.iter().map(|msg| msg.uid));
+
+        if let Some(keep) = filter.keep_recent_per_sender {
+            let (kept, expired) = split_recent_per_sender(matched, keep, options.age_basis);
+            info!(
+                "{} Keeping {} most recent message(s) per sender, acting on {} older",
+                scope("filter", &filter.name),
+                kept.len(),
+                expired.len()
+            );
+            matched = expired;
+            claimed.extend(kept.iter().map(|msg| msg.uid));
+        }
+
+        if filter.apply_to_thread {
+            matched = expand_thread(filter, matched, &claimed);
+            claimed.extend(matched.iter().map(|msg| msg.uid));
+            if options.strategy == FilterStrategy::FirstMatch {
+                messages.retain(|msg| !claimed.contains(&msg.uid));
+            }
+        }
+
+        results.push((filter, matched));
+    }
+
+    results
+}
+
+/// UIDs found in the matched messages' threads that aren't in `exclude`
+/// (the matched messages themselves, and any already spoken for), in
+/// ascending order.
+pub fn thread_expansion_uids(thread_uids: &HashSet<u32>, exclude: &HashSet<u32>) -> Vec<u32> {
+    let mut uids: Vec<u32> = thread_uids.difference(exclude).copied().collect();
+    uids.sort_unstable();
+    uids
+}
+
+/// Adds the other INBOX messages of every matched message's thread, found by
+/// searching `X-GM-THRID`, leaving out UIDs in `exclude`. Lookup failures are
+/// logged and leave `matched` as is.
+fn expand_threads<C: ImapOps>(
+    client: &mut C,
+    mut matched: Vec<Message>,
+    exclude: &HashSet<u32>,
+    tag: &str,
+    progress_interval: usize,
+) -> Vec<Message> {
+    let threads: BTreeSet<u64> = matched.iter().filter_map(|msg| msg.thread_id).collect();
+    let mut thread_uids = HashSet::new();
+    for thread in threads {
+        match client.uid_search(&format!("X-GM-THRID {}", thread)) {
+            Ok(uids) => thread_uids.extend(uids),
+            Err(e) => warn!("{} Could not look up thread {}: {}", tag, thread, e),
+        }
+    }
+
+    let exclude: HashSet<u32> = exclude
+        .iter()
+        .copied()
+        .chain(matched.iter().map(|msg| msg.uid))
+        .collect();
+    let extra = thread_expansion_uids(&thread_uids, &exclude);
+    if extra.is_empty() {
+        return matched;
+    }
+    let uid_set = extra
+        .iter()
+        .map(|uid| uid.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    match client.uid_fetch(&uid_set, FETCH_ITEMS) {
+        Ok(fetches) => {
+            let (messages, _) = build_messages(fetches, progress_interval);
+            info!(
+                "{} Acting on {} more message(s) from matched threads",
+                tag,
+                messages.len()
+            );
+            matched.extend(messages);
+        }
+        Err(e) => warn!("{} Could not fetch thread messages {}: {}", tag, uid_set, e),
+    }
+    matched
+}
+
+/// Groups messages by sender and splits each group into the `keep` newest
+/// messages (by `basis`) and the older remainder. Returns `(kept, rest)`.
+pub fn split_recent_per_sender(
+    messages: Vec<Message>,
+    keep: usize,
+    basis: AgeBasis,
+) -> (Vec<Message>, Vec<Message>) {
+    let mut by_sender: HashMap<String, Vec<Message>> = HashMap::new();
+    for msg in messages {
+        by_sender.entry(msg.sender_key()).or_default().push(msg);
+    }
+
+    let mut kept = Vec::new();
+    let mut rest = Vec::new();
+    for (_, mut group) in by_sender {
+        // Newest first; messages without a date sort last so they are never kept over dated ones
+        group.sort_by_key(|msg| std::cmp::Reverse(msg.timestamp(basis)));
+        let older = group.split_off(keep.min(group.len()));
+        kept.extend(group);
+        rest.extend(older);
+    }
+
+    (kept, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_filter::AddressFilter;
+    use crate::client::mock::MockClient;
+    use crate::message_filter::{MoveTarget, DEFAULT_FILTER_NAME};
+    use chrono::TimeDelta;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn dated_message(uid: u32, from: &str, days_ago: i64) -> Message {
+        let now = DateTime::parse_from_rfc3339("2025-01-31T12:00:00+00:00").unwrap();
+        Message {
+            uid,
+            from: vec![("".to_string(), from.to_string())],
+            internal_date: Some(now - TimeDelta::days(days_ago)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_split_recent_per_sender_keeps_newest_n() {
+        let messages = vec![
+            dated_message(1, "alerts@vendor.com", 5),
+            dated_message(2, "alerts@vendor.com", 1),
+            dated_message(3, "alerts@vendor.com", 3),
+            dated_message(4, "alerts@vendor.com", 2),
+            dated_message(5, "news@other.com", 10),
+        ];
+
+        let (kept, rest) = split_recent_per_sender(messages, 2, AgeBasis::Internaldate);
+
+        let mut kept_uids: Vec<_> = kept.iter().map(|m| m.uid).collect();
+        let mut rest_uids: Vec<_> = rest.iter().map(|m| m.uid).collect();
+        kept_uids.sort();
+        rest_uids.sort();
+
+        assert_eq!(kept_uids, vec![2, 4, 5]);
+        assert_eq!(rest_uids, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_split_recent_per_sender_by_date_header() {
+        // Imported mail: INTERNALDATE says 1 is newest, the Date header says 2 is
+        let mut imported = dated_message(1, "alerts@vendor.com", 0);
+        imported.date = Some(
+            DateTime::parse_from_rfc3339("2020-01-01T00:00:00Z")
+                .unwrap()
+                .to_utc(),
+        );
+        let mut recent = dated_message(2, "alerts@vendor.com", 3);
+        recent.date = Some(
+            DateTime::parse_from_rfc3339("2025-01-28T00:00:00Z")
+                .unwrap()
+                .to_utc(),
+        );
+
+        let (kept, _) = split_recent_per_sender(
+            vec![imported.clone(), recent.clone()],
+            1,
+            AgeBasis::Internaldate,
+        );
+        assert_eq!(kept[0].uid, 1);
+
+        let (kept, _) = split_recent_per_sender(vec![imported, recent], 1, AgeBasis::DateHeader);
+        assert_eq!(kept[0].uid, 2);
+    }
+
+    #[test]
+    fn test_split_recent_per_sender_is_case_insensitive() {
+        let messages = vec![
+            dated_message(1, "Alerts@Vendor.com", 1),
+            dated_message(2, "alerts@vendor.com", 2),
+        ];
+
+        let (kept, rest) = split_recent_per_sender(messages, 1, AgeBasis::Internaldate);
+
+        assert_eq!(kept.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(rest.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![2]);
+    }
+
+    fn from_filter(name: &str, pattern: &str) -> MessageFilter {
+        MessageFilter {
+            name: name.to_string(),
+            from: Some(AddressFilter::new(vec![pattern.to_string()]).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_all_match_skips_messages_an_earlier_filter_moved_away() {
+        for (capabilities, moves) in [
+            (&["IMAP4REV1", "MOVE"][..], vec!["UID MOVE 1 Archive"]),
+            (
+                &["IMAP4REV1", "X-GM-EXT-1"][..],
+                vec![
+                    "UID STORE 1 +X-GM-LABELS \"Archive\"",
+                    "UID STORE 1 +X-GM-LABELS \"Boss\"",
+                ],
+            ),
+        ] {
+            let client = MockClient::default()
+                .with_capabilities(capabilities)
+                .with_mailboxes(&["Archive", "Boss"])
+                .with_message(1, &raw_message("boss@tatari.tv", "me@tatari.tv", "plan"));
+            let filters = vec![
+                move_filter("archive", "Archive"),
+                MessageFilter {
+                    move_to: Some("Boss".into()),
+                    ..from_filter("boss", "boss@tatari.tv")
+                },
+            ];
+
+            let mut imap_filter = IMAPFilter::with_client(client, filters, all_match());
+            imap_filter.execute().unwrap();
+
+            let client = imap_filter.client();
+            let calls = [client.calls_to("UID MOVE"), client.calls_to("UID STORE")].concat();
+            assert_eq!(calls, moves, "{:?}", capabilities);
+        }
+    }
+
+    fn all_match() -> FilterOptions {
+        FilterOptions {
+            strategy: FilterStrategy::AllMatch,
+            ..Default::default()
+        }
+    }
+
+    fn matched_uids(results: &[(&MessageFilter, Vec<Message>)]) -> Vec<(String, Vec<u32>)> {
+        results
+            .iter()
+            .map(|(filter, msgs)| (filter.name.clone(), msgs.iter().map(|m| m.uid).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_match_filters_first_match_consumes_message() {
+        let filters = vec![
+            from_filter("company", "*@tatari.tv"),
+            from_filter("me", "scott.idler@tatari.tv"),
+        ];
+        let messages = vec![dated_message(1, "scott.idler@tatari.tv", 0)];
+
+        let results = match_filters(
+            &filters,
+            messages,
+            &FilterOptions::default(),
+            |_, matched, _| matched,
+        );
+
+        assert_eq!(
+            matched_uids(&results),
+            vec![("company".to_string(), vec![1]), ("me".to_string(), vec![])]
+        );
+    }
+
+    #[test]
+    fn test_match_filters_all_match_applies_every_filter() {
+        let filters = vec![
+            from_filter("company", "*@tatari.tv"),
+            from_filter("me", "scott.idler@tatari.tv"),
+        ];
+        let messages = vec![dated_message(1, "scott.idler@tatari.tv", 0)];
+
+        let results = match_filters(&filters, messages, &all_match(), |_, matched, _| matched);
+
+        assert_eq!(
+            matched_uids(&results),
+            vec![
+                ("company".to_string(), vec![1]),
+                ("me".to_string(), vec![1])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_messages_skips_incomplete_fetches() {
+        let date = DateTime::parse_from_rfc3339("2025-01-31T12:00:00+00:00").unwrap();
+        let body = b"From: a@example.com\r\nSubject: hi\r\n\r\nbody".to_vec();
+        let fetch = |seq: u32, body: Option<Vec<u8>>, internal_date| FetchedMessage {
+            seq,
+            uid: Some(seq + 100),
+            body,
+            internal_date,
+            ..Default::default()
+        };
+        let mut fetched = vec![
+            fetch(1, Some(body.clone()), Some(date)),
+            fetch(2, None, Some(date)),
+            fetch(3, Some(body.clone()), None),
+            fetch(4, Some(body.clone()), Some(date)),
+            fetch(5, Some(body), Some(date)),
+        ];
+        fetched[4].uid = None;
+        fetched[3].flags.push("\\Seen".to_string());
+
+        let (messages, skipped) = build_messages(fetched, DEFAULT_PROGRESS_INTERVAL);
+
+        assert_eq!(
+            messages.iter().map(|m| m.uid).collect::<Vec<_>>(),
+            vec![101, 103, 104]
+        );
+        assert_eq!(skipped, vec![2, 5], "no body, then no UID");
+        assert!(messages[1].internal_date.is_none());
+        assert_eq!(
+            messages.iter().map(|m| m.seen).collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_should_log_progress_boundaries() {
+        assert!(!should_log_progress(1, 1200, 500));
+        assert!(!should_log_progress(499, 1200, 500));
+        assert!(should_log_progress(500, 1200, 500));
+        assert!(!should_log_progress(501, 1200, 500));
+        assert!(should_log_progress(1000, 1200, 500));
+        assert!(should_log_progress(1200, 1200, 500));
+        assert!(
+            should_log_progress(3, 3, 500),
+            "the last item always reports"
+        );
+        assert!(!should_log_progress(500, 1200, 0), "0 disables progress");
+        assert!(!should_log_progress(0, 0, 500));
+    }
+
+    fn raw_message(from: &str, to: &str, subject: &str) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\nbody\r\n",
+            from, to, subject
+        )
+    }
+
+    #[test]
+    fn test_execute_moves_and_stars_matched_messages() {
+        let client = MockClient::default()
+            .with_message(
+                41,
+                &raw_message("boss@tatari.tv", "scott.idler@tatari.tv", "review"),
+            )
+            .with_message(
+                42,
+                &raw_message("news@example.com", "scott.idler@tatari.tv", "weekly"),
+            );
+        let filters = vec![MessageFilter {
+            name: "work".to_string(),
+            from: Some(AddressFilter::new(vec!["*@tatari.tv".to_string()]).unwrap()),
+            move_to: Some("Work".into()),
+            star: Some(true),
+            ..Default::default()
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            summary,
+            Summary {
+                fetched: 2,
+                matched: 1,
+                applied: 2,
+                errors: 0,
+                interrupted: false,
+                timed_out: false,
+                filters: vec![FilterCounts {
+                    name: "work".to_string(),
+                    matched: 1,
+                    moved: 1,
+                    appended: 0,
+                    starred: 1,
+                    failed: 0
+                }],
+                actions: ["star", "move:Work"]
+                    .into_iter()
+                    .map(|action| ActionRecord {
+                        uid: 41,
+                        seq: 1,
+                        subject: "review".to_string(),
+                        from: "boss@tatari.tv".to_string(),
+                        action: action.to_string(),
+                        result: "ok".to_string(),
+                    })
+                    .collect(),
+            }
+        );
+        let client = imap_filter.client();
+        assert_eq!(
+            client.calls_to("UID STORE"),
+            vec![
+                "UID STORE 41 +X-GM-LABELS (\\Starred)",
+                "UID STORE 41 +X-GM-LABELS \"Work\""
+            ]
+        );
+        assert_eq!(client.calls.last().map(String::as_str), Some("LOGOUT"));
+    }
+
+    fn move_filter(name: &str, destination: &str) -> MessageFilter {
+        MessageFilter {
+            move_to: Some(destination.into()),
+            ..from_filter(name, "*")
+        }
+    }
+
+    #[test]
+    fn test_filters_read_from_their_own_mailbox() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "first"))
+            .with_mailboxes(&["Archive", "Spam review"]);
+        let spam_review = |filter: MessageFilter| MessageFilter {
+            mailbox: Some("Spam review".to_string()),
+            ..filter
+        };
+        let filters = vec![
+            spam_review(move_filter("spam", "Archive")),
+            move_filter("inbox", "Archive"),
+            spam_review(from_filter("vip", "boss@*")),
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(
+            client.calls_to("SELECT"),
+            vec!["SELECT Spam review", "SELECT INBOX"]
+        );
+        assert_eq!(client.calls_to("SEARCH"), vec!["SEARCH ALL", "SEARCH ALL"]);
+        assert_eq!(summary.fetched, 2);
+        assert_eq!(
+            summary
+                .filters
+                .iter()
+                .map(|counts| counts.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["spam", "vip", "inbox"]
+        );
+    }
+
+    #[test]
+    fn test_move_to_labels_with_spaces_round_trips() {
+        let client = MockClient::default()
+            .with_message(
+                1,
+                &raw_message("news@team.com", "me@example.com", "standup"),
+            )
+            .with_message(
+                2,
+                &raw_message("billing@acme.com", "me@example.com", "invoice"),
+            )
+            .with_mailboxes(&["Team Updates", "Clients/Acme Corp"]);
+        let filters = vec![
+            MessageFilter {
+                move_to: Some("Team Updates".into()),
+                ..from_filter("team", "*@team.com")
+            },
+            MessageFilter {
+                move_to: Some("Clients/Acme Corp".into()),
+                ..from_filter("acme", "*@acme.com")
+            },
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        imap_filter.execute().unwrap();
+
+        let client = &mut imap_filter.client;
+        assert_eq!(
+            client.calls_to("UID STORE"),
+            vec![
+                "UID STORE 1 +X-GM-LABELS \"Team Updates\"",
+                "UID STORE 2 +X-GM-LABELS \"Clients/Acme Corp\""
+            ]
+        );
+        let labels = client.uid_fetch_labels("1,2").unwrap();
+        assert_eq!(labels[&1], vec!["Team Updates"]);
+        assert_eq!(labels[&2], vec!["Clients/Acme Corp"]);
+    }
+
+    fn capabilities(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_move_method_selection() {
+        assert_eq!(
+            MoveMethod::select(&capabilities(&["IMAP4REV1", "X-GM-EXT-1", "MOVE"])),
+            MoveMethod::GmailLabel
+        );
+        assert_eq!(
+            MoveMethod::select(&capabilities(&["IMAP4REV1", "MOVE"])),
+            MoveMethod::Move
+        );
+        assert_eq!(
+            MoveMethod::select(&capabilities(&["IMAP4REV1"])),
+            MoveMethod::CopyExpunge { uidplus: false }
+        );
+        assert_eq!(
+            MoveMethod::select(&capabilities(&["IMAP4REV1", "UIDPLUS"])),
+            MoveMethod::CopyExpunge { uidplus: true }
+        );
+    }
+
+    #[test]
+    fn test_move_without_move_capability_uses_copy() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "old"))
+            .with_mailboxes(&["Archive"])
+            .with_capabilities(&["IMAP4REV1"]);
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("archive", "Archive")],
+            FilterOptions::default(),
+        );
+        imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(client.calls_to("UID COPY"), vec!["UID COPY 1 Archive"]);
+        assert!(client.calls_to("UID MOVE").is_empty());
+        assert!(client.calls_to("UID STORE 1 +X-GM-LABELS").is_empty());
+    }
+
+    #[test]
+    fn test_append_copies_body_flags_and_internal_date() {
+        let date = DateTime::parse_from_rfc3339("2025-03-07T10:00:00+01:00").unwrap();
+        let message = FetchedMessage {
+            seq: 1,
+            uid: Some(7),
+            body: Some(raw_message("vendor@acme.com", "me@example.com", "invoice").into_bytes()),
+            internal_date: Some(date),
+            flags: vec!["\\Seen".to_string(), "\\Recent".to_string()],
+            ..Default::default()
+        };
+        let filters = vec![MessageFilter {
+            append_to: Some("Vendors".to_string()),
+            ..from_filter("vendor", "*@acme.com")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(
+            MockClient::new(vec![message.clone()]).with_mailboxes(&["Vendors"]),
+            filters,
+            FilterOptions::default(),
+        );
+        let summary = imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(
+            client.calls_to("UID FETCH 7"),
+            vec![format!("UID FETCH 7 {}", APPEND_FETCH_ITEMS)]
+        );
+        let length = message.body.unwrap().len();
+        assert_eq!(
+            client.calls_to("APPEND"),
+            vec![format!(
+                "APPEND Vendors (\\Seen) \"07-Mar-2025 10:00:00 +0100\" {{{}}}",
+                length
+            )]
+        );
+        assert_eq!((summary.applied, summary.filters[0].appended), (1, 1));
+        assert_eq!(summary.actions[0].action, "append:Vendors");
+    }
+
+    #[test]
+    fn test_append_target_is_created_and_appended_before_moving() {
+        let client = MockClient::default()
+            .with_message(
+                1,
+                &raw_message("vendor@acme.com", "me@example.com", "invoice"),
+            )
+            .with_capabilities(&["IMAP4REV1", "MOVE"]);
+        let filters = vec![MessageFilter {
+            append_to: Some("Vendors".to_string()),
+            ..move_filter("vendor", "Archive")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(
+            client.calls_to("CREATE"),
+            vec!["CREATE Archive", "CREATE Vendors"]
+        );
+        let append = client
+            .calls
+            .iter()
+            .position(|call| call.starts_with("APPEND Vendors"))
+            .unwrap();
+        let moved = client
+            .calls
+            .iter()
+            .position(|call| call == "UID MOVE 1 Archive")
+            .unwrap();
+        assert!(append < moved, "{:?}", client.calls);
+    }
+
+    #[test]
+    fn test_keep_in_inbox_copies_instead_of_moving() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "old"))
+            .with_mailboxes(&["Archive"])
+            .with_capabilities(&["IMAP4REV1", "MOVE"]);
+        let target = MoveTarget {
+            folder: "Archive".to_string(),
+            keep_in_inbox: true,
+        };
+        let filters = vec![MessageFilter {
+            move_to: Some(target),
+            ..from_filter("archive", "*")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(client.calls_to("UID COPY"), vec!["UID COPY 1 Archive"]);
+        assert!(client.calls_to("UID MOVE").is_empty());
+        assert!(client.calls_to("UID STORE").is_empty());
+        assert_eq!(summary.actions[0].action, "copy:Archive");
+    }
+
+    #[test]
+    fn test_keep_in_inbox_on_gmail_only_labels() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "old"))
+            .with_mailboxes(&["Archive"]);
+        let target = MoveTarget {
+            folder: "Archive".to_string(),
+            keep_in_inbox: true,
+        };
+        let filters = vec![MessageFilter {
+            move_to: Some(target),
+            ..from_filter("archive", "*")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec!["UID STORE 1 +X-GM-LABELS \"Archive\""]
+        );
+        assert!(imap_filter.client().calls_to("UID MOVE").is_empty());
+    }
+
+    #[test]
+    fn test_move_with_move_capability_uses_uid_move() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "old"))
+            .with_mailboxes(&["Archive"])
+            .with_capabilities(&["IMAP4REV1", "MOVE"]);
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("archive", "Archive")],
+            FilterOptions::default(),
+        );
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("UID MOVE"),
+            vec!["UID MOVE 1 Archive"]
+        );
+        assert!(imap_filter.client().calls_to("UID STORE").is_empty());
+    }
+
+    #[test]
+    fn test_star_method_selection() {
+        assert_eq!(
+            StarMethod::select(&capabilities(&["IMAP4REV1", "X-GM-EXT-1"])),
+            StarMethod::GmailLabel
+        );
+        assert_eq!(
+            StarMethod::select(&capabilities(&["IMAP4REV1", "MOVE"])),
+            StarMethod::Flagged
+        );
+    }
+
+    #[test]
+    fn test_star_on_non_gmail_server_sets_flagged_before_moving() {
+        let mut client = MockClient::default()
+            .with_capabilities(&["IMAP4REV1", "MOVE"])
+            .with_message(
+                1,
+                &raw_message("boss@example.com", "me@example.com", "urgent"),
+            )
+            .with_message(
+                2,
+                &raw_message("boss@example.com", "me@example.com", "also urgent"),
+            )
+            .with_mailboxes(&["Work"]);
+        client.inbox[1].flags.push("\\Flagged".to_string());
+        let filters = vec![MessageFilter {
+            star: Some(true),
+            move_to: Some("Work".into()),
+            ..from_filter("vip", "boss@*")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(client.calls_to("UID SEARCH"), vec!["UID SEARCH FLAGGED"]);
+        assert_eq!(
+            client.calls_to("UID STORE"),
+            vec!["UID STORE 1 +FLAGS (\\Flagged)"]
+        );
+        assert_eq!(
+            client.calls_to("UID MOVE"),
+            vec!["UID MOVE 1 Work", "UID MOVE 2 Work"]
+        );
+        assert_eq!((summary.applied, summary.errors), (3, 0));
+    }
+
+    fn failing_store_client() -> MockClient {
+        MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "first"))
+            .with_message(2, &raw_message("b@example.com", "me@example.com", "second"))
+            .with_mailboxes(&["Archive"])
+            .with_failure("UID STORE 1 ")
+    }
+
+    #[test]
+    fn test_failed_action_is_logged_and_run_continues() {
+        let mut imap_filter = IMAPFilter::with_client(
+            failing_store_client(),
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        );
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            (summary.matched, summary.applied, summary.errors),
+            (2, 1, 1)
+        );
+        assert_eq!(summary.exit_code(), EXIT_ACTION_ERRORS);
+        assert_eq!(imap_filter.client().calls_to("UID STORE").len(), 2);
+        assert_eq!(
+            imap_filter.client().calls.last().map(String::as_str),
+            Some("LOGOUT")
+        );
+    }
+
+    #[test]
+    fn test_fail_fast_stops_on_first_failed_action() {
+        let options = FilterOptions {
+            fail_fast: true,
+            ..Default::default()
+        };
+        let mut imap_filter = IMAPFilter::with_client(
+            failing_store_client(),
+            vec![move_filter("all", "Archive")],
+            options,
+        );
+        let err = imap_filter.execute().unwrap_err();
+
+        assert!(err.to_string().contains("UID 1"), "{}", err);
+        assert!(
+            matches!(err, FilterError::Action { source, .. } if matches!(*source, FilterError::Imap(imap::Error::Bad(_))))
+        );
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec!["UID STORE 1 +X-GM-LABELS \"Archive\""]
+        );
+        assert_eq!(
+            imap_filter.client().calls.last().map(String::as_str),
+            Some("LOGOUT")
+        );
+    }
+
+    #[test]
+    fn test_already_starred_message_is_not_starred_again() {
+        let mut client = MockClient::default()
+            .with_message(1, &raw_message("boss@tatari.tv", "me@tatari.tv", "old"))
+            .with_message(2, &raw_message("boss@tatari.tv", "me@tatari.tv", "new"));
+        client.inbox[0].labels.push("\\Starred".to_string());
+        let filters = vec![MessageFilter {
+            star: Some(true),
+            ..from_filter("boss", "boss@tatari.tv")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(summary.applied, 1);
+        assert_eq!(
+            imap_filter.client().calls_to("UID SEARCH"),
+            vec!["UID SEARCH X-GM-LABELS \\Starred"]
+        );
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec!["UID STORE 2 +X-GM-LABELS (\\Starred)"]
+        );
+    }
+
+    #[test]
+    fn test_stop_flag_skips_remaining_messages_and_logs_out() {
+        let client = (1..=3).fold(
+            MockClient::default().with_mailboxes(&["Archive"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let stop = Arc::new(AtomicBool::new(true));
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        )
+        .with_stop_flag(stop);
+        let summary = imap_filter.execute().unwrap();
+
+        assert!(summary.interrupted);
+        assert_eq!(summary.applied, 0);
+        assert!(imap_filter.client().calls_to("UID STORE").is_empty());
+        assert_eq!(
+            imap_filter.client().calls.last().map(String::as_str),
+            Some("LOGOUT")
+        );
+    }
+
+    #[test]
+    fn test_max_affected_skips_the_whole_filter() {
+        let client = (1..=3).fold(
+            MockClient::default().with_mailboxes(&["Archive"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let broad = MessageFilter {
+            max_affected: Some(2),
+            ..move_filter("broad", "Archive")
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![broad], FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            (summary.matched, summary.applied, summary.errors),
+            (3, 0, 1)
+        );
+        assert!(imap_filter.client().calls_to("UID STORE").is_empty());
+        assert!(imap_filter.client().calls_to("UID MOVE").is_empty());
+        assert_eq!(summary.exit_code(), EXIT_ACTION_ERRORS);
+    }
+
+    #[test]
+    fn test_trace_message_decisions() {
+        let msg = Message::new(
+            7,
+            raw_message("alerts@vendor.com", "me@example.com", "Weekly digest").into_bytes(),
+        );
+        let filters = vec![
+            MessageFilter {
+                name: "vendor".to_string(),
+                from: Some(AddressFilter::new(vec!["*@vendor.com".to_string()]).unwrap()),
+                ..Default::default()
+            },
+            MessageFilter {
+                name: "not-me".to_string(),
+                to: Some(AddressFilter::new(vec!["boss@example.com".to_string()]).unwrap()),
+                ..Default::default()
+            },
+            MessageFilter {
+                name: "old".to_string(),
+                older_than: Some(TimeDelta::days(1)),
+                ..Default::default()
+            },
+        ];
+
+        let decisions = trace_message(&msg, &filters, &FilterOptions::default(), Utc::now());
+
+        let decision = |filter: &str, from, to, matches| FilterDecision {
+            filter: filter.to_string(),
+            from,
+            to,
+            cc: true,
+            matches,
+        };
+        assert_eq!(
+            decisions,
+            vec![
+                decision("vendor", true, true, true),
+                decision("not-me", true, false, false),
+                decision("old", true, true, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_catch_all_gets_only_unmatched_messages() {
+        for strategy in [FilterStrategy::FirstMatch, FilterStrategy::AllMatch] {
+            let client = MockClient::default()
+                .with_message(
+                    1,
+                    &raw_message("boss@tatari.tv", "me@example.com", "review"),
+                )
+                .with_message(
+                    2,
+                    &raw_message("news@example.com", "me@example.com", "weekly"),
+                )
+                .with_message(
+                    3,
+                    &raw_message("friend@example.org", "me@example.com", "lunch"),
+                )
+                .with_mailboxes(&["Work", "Triage"]);
+            let filters = vec![
+                MessageFilter {
+                    move_to: Some("Work".into()),
+                    ..from_filter("work", "*@tatari.tv")
+                },
+                MessageFilter {
+                    name: DEFAULT_FILTER_NAME.to_string(),
+                    catch_all: true,
+                    move_to: Some("Triage".into()),
+                    ..Default::default()
+                },
+            ];
+            let options = FilterOptions {
+                strategy,
+                ..Default::default()
+            };
+            let mut imap_filter = IMAPFilter::with_client(client, filters, options);
+            imap_filter.execute().unwrap();
+
+            assert_eq!(
+                imap_filter.client().calls_to("UID STORE"),
+                vec![
+                    "UID STORE 1 +X-GM-LABELS \"Work\"",
+                    "UID STORE 2 +X-GM-LABELS \"Triage\"",
+                    "UID STORE 3 +X-GM-LABELS \"Triage\""
+                ],
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn test_actions_are_written_to_the_audit_log() {
+        let path = std::env::temp_dir().join(format!(
+            "imap-filter-run-audit-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let client = MockClient::default()
+            .with_message(
+                41,
+                &raw_message("boss@tatari.tv", "me@example.com", "review"),
+            )
+            .with_message(
+                42,
+                &raw_message("news@example.com", "me@example.com", "weekly"),
+            )
+            .with_mailboxes(&["Work"])
+            .with_failure("UID STORE 41 +X-GM-LABELS \"Work\"");
+        let filters = vec![MessageFilter {
+            move_to: Some("Work".into()),
+            star: Some(true),
+            ..from_filter("work", "*@tatari.tv")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default())
+            .with_audit_log(AuditLog::open(&path).unwrap());
+        imap_filter.execute().unwrap();
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let entries: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line["uid"].as_u64().unwrap(),
+                    line["action"].as_str().unwrap(),
+                    line["result"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(41, "star", "ok"), (41, "move:Work", "failed")]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_runtime_exceeded() {
+        let started = Instant::now();
+        let budget = Some(Duration::from_secs(60));
+
+        assert!(!runtime_exceeded(
+            started,
+            None,
+            started + Duration::from_secs(3600)
+        ));
+        assert!(!runtime_exceeded(started, budget, started));
+        assert!(!runtime_exceeded(
+            started,
+            budget,
+            started + Duration::from_secs(59)
+        ));
+        assert!(runtime_exceeded(
+            started,
+            budget,
+            started + Duration::from_secs(60)
+        ));
+        assert!(runtime_exceeded(started, Some(Duration::ZERO), started));
+    }
+
+    #[test]
+    fn test_max_runtime_stops_and_logs_out() {
+        let client = (1..=3).fold(
+            MockClient::default().with_mailboxes(&["Archive"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let options = FilterOptions {
+            max_runtime: Some(Duration::ZERO),
+            ..Default::default()
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![move_filter("all", "Archive")], options);
+        let summary = imap_filter.execute().unwrap();
+
+        assert!(summary.timed_out && summary.interrupted);
+        assert_eq!((summary.exit_code(), summary.applied), (EXIT_TIMED_OUT, 0));
+        assert!(imap_filter.client().calls_to("UID STORE").is_empty());
+        assert_eq!(
+            imap_filter.client().calls.last().map(String::as_str),
+            Some("LOGOUT")
+        );
+    }
+
+    #[test]
+    fn test_thread_expansion_uids() {
+        let matched: HashSet<u32> = [5, 2].into_iter().collect();
+        let thread_uids: HashSet<u32> = [2, 9, 5, 7].into_iter().collect();
+        assert_eq!(thread_expansion_uids(&thread_uids, &matched), vec![7, 9]);
+        assert!(thread_expansion_uids(&[2, 5].into_iter().collect(), &matched).is_empty());
+        let kept: HashSet<u32> = [2, 5, 9].into_iter().collect();
+        assert_eq!(
+            thread_expansion_uids(&thread_uids, &kept),
+            vec![7],
+            "kept messages stay out"
+        );
+    }
+
+    #[test]
+    fn test_apply_to_thread_acts_on_whole_thread() {
+        let mut client = MockClient::default()
+            .with_mailboxes(&["Archive"])
+            .with_message(1, &raw_message("boss@tatari.tv", "me@tatari.tv", "plan"))
+            .with_message(
+                2,
+                &raw_message("me@tatari.tv", "boss@tatari.tv", "Re: plan"),
+            )
+            .with_message(
+                3,
+                &raw_message("other@example.com", "me@tatari.tv", "unrelated"),
+            );
+        client.inbox[0].thread_id = Some(77);
+        client.inbox[1].thread_id = Some(77);
+        client.inbox[2].thread_id = Some(88);
+        let filters = vec![MessageFilter {
+            apply_to_thread: true,
+            move_to: Some("Archive".into()),
+            ..from_filter("boss", "boss@tatari.tv")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(summary.matched, 2);
+        let client = imap_filter.client();
+        assert_eq!(
+            client.calls_to("UID SEARCH"),
+            vec!["UID SEARCH X-GM-THRID 77"]
+        );
+        assert_eq!(
+            client.calls_to("UID STORE"),
+            vec![
+                "UID STORE 1 +X-GM-LABELS \"Archive\"",
+                "UID STORE 2 +X-GM-LABELS \"Archive\""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_thread_siblings_are_claimed_before_later_filters() {
+        let mut client = MockClient::default()
+            .with_mailboxes(&["Archive"])
+            .with_message(1, &raw_message("boss@tatari.tv", "me@tatari.tv", "plan"))
+            .with_message(
+                2,
+                &raw_message("me@tatari.tv", "boss@tatari.tv", "Re: plan"),
+            )
+            .with_message(
+                3,
+                &raw_message("other@example.com", "me@tatari.tv", "unrelated"),
+            );
+        client.inbox[0].thread_id = Some(77);
+        client.inbox[1].thread_id = Some(77);
+        let filters = vec![
+            MessageFilter {
+                apply_to_thread: true,
+                move_to: Some("Archive".into()),
+                ..from_filter("boss", "boss@tatari.tv")
+            },
+            MessageFilter {
+                star: Some(true),
+                ..from_filter("everyone", "*")
+            },
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            summary
+                .filters
+                .iter()
+                .map(|counts| counts.matched)
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec![
+                "UID STORE 1 +X-GM-LABELS \"Archive\"",
+                "UID STORE 2 +X-GM-LABELS \"Archive\"",
+                "UID STORE 3 +X-GM-LABELS (\\Starred)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_throttle_sleeps_between_mutating_calls() {
+        let client = (1..=3).fold(
+            MockClient::default().with_mailboxes(&["Archive"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let filters = vec![MessageFilter {
+            star: Some(true),
+            ..move_filter("all", "Archive")
+        }];
+        let options = FilterOptions {
+            throttle: Duration::from_millis(250),
+            ..Default::default()
+        };
+        let sleeps = Rc::new(RefCell::new(Vec::new()));
+        let recorded = sleeps.clone();
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, options)
+            .with_sleep(move |delay| recorded.borrow_mut().push(delay));
+        imap_filter.execute().unwrap();
+
+        // 3 moves + 3 stars, with a pause before every call but the first
+        assert_eq!(imap_filter.client().calls_to("UID STORE").len(), 6);
+        assert_eq!(*sleeps.borrow(), vec![Duration::from_millis(250); 5]);
+    }
+
+    #[test]
+    fn test_zero_throttle_never_sleeps() {
+        let client = MockClient::default()
+            .with_mailboxes(&["Archive"])
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "one"))
+            .with_message(2, &raw_message("a@example.com", "me@example.com", "two"));
+        let sleeps = Rc::new(RefCell::new(0));
+        let recorded = sleeps.clone();
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        )
+        .with_sleep(move |_| *recorded.borrow_mut() += 1);
+        imap_filter.execute().unwrap();
+
+        assert_eq!(*sleeps.borrow(), 0);
+    }
+
+    #[test]
+    fn test_inbox_query_is_passed_to_search() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "new"));
+        let options = FilterOptions {
+            inbox_query: "UNSEEN".to_string(),
+            ..Default::default()
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(client, vec![], options);
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("SEARCH"),
+            vec!["SEARCH UNSEEN"]
+        );
+    }
+
+    #[test]
+    fn test_references_narrow_the_search_to_one_thread() {
+        let options = FilterOptions {
+            references: Some("<root@example.com>".to_string()),
+            ..Default::default()
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(MockClient::default(), vec![], options);
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("SEARCH"),
+            vec!["SEARCH OR HEADER REFERENCES \"<root@example.com>\" HEADER IN-REPLY-TO \"<root@example.com>\""]
+        );
+    }
+
+    #[test]
+    fn test_gmail_search_is_sent_as_x_gm_raw() {
+        let options = FilterOptions {
+            inbox_query: "UNSEEN".to_string(),
+            gmail_search: Some("has:attachment larger:5M".to_string()),
+            ..Default::default()
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(MockClient::default(), vec![], options);
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("SEARCH"),
+            vec!["SEARCH UNSEEN X-GM-RAW \"has:attachment larger:5M\""]
+        );
+    }
+
+    #[test]
+    fn test_gmail_search_is_rejected_on_non_gmail_servers() {
+        let client = MockClient::default().with_capabilities(&["IMAP4REV1", "MOVE"]);
+        let options = FilterOptions {
+            gmail_search: Some("is:unread".to_string()),
+            ..Default::default()
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(client, vec![], options);
+        let err = imap_filter.execute().unwrap_err();
+
+        assert!(err.to_string().contains("gmail_search"), "{}", err);
+        assert!(imap_filter.client().calls_to("SEARCH").is_empty());
+    }
+
+    #[test]
+    fn test_invalid_inbox_query_is_rejected_before_search() {
+        let options = FilterOptions {
+            inbox_query: "(UNSEEN".to_string(),
+            ..Default::default()
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(MockClient::default(), vec![], options);
+        assert!(matches!(
+            imap_filter.execute(),
+            Err(FilterError::InvalidQuery(_))
+        ));
+        assert!(imap_filter.client().calls_to("SEARCH").is_empty());
+    }
+
+    #[test]
+    fn test_dropped_session_is_reconnected_once() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "first"))
+            .with_message(2, &raw_message("b@example.com", "me@example.com", "second"))
+            .with_mailboxes(&["Archive"])
+            .with_disconnect("UID STORE 1 ");
+        let reconnector = Reconnector::new(MAX_RECONNECTS, || Ok(MockClient::default()));
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        )
+        .with_reconnector(reconnector);
+        imap_filter.execute().unwrap();
+
+        // The fresh session re-selects INBOX, retries UID 1, and carries on
+        assert_eq!(
+            imap_filter.client().calls,
+            vec![
+                "SELECT INBOX",
+                "UID STORE 1 +X-GM-LABELS \"Archive\"",
+                "UID STORE 2 +X-GM-LABELS \"Archive\"",
+                "LOGOUT"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reconnects_are_capped() {
+        let mut client = MockClient::default().with_disconnect("UID STORE");
+        let mut reconnector = Some(Reconnector::new(1, || {
+            Ok(MockClient::default().with_disconnect("UID STORE"))
+        }));
+
+        let result = Reconnector::retry(&mut reconnector, &mut client, "INBOX", |client| {
+            client.uid_store("1", "+FLAGS (\\Seen)")
+        });
+        assert!(result.is_err_and(|e| is_disconnect(&e)));
+
+        let result = Reconnector::retry(&mut reconnector, &mut client, "INBOX", |client| {
+            client.uid_store("1", "+FLAGS (\\Seen)")
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            client.calls_to("SELECT").len(),
+            1,
+            "no second reconnect once the budget is spent"
+        );
+    }
+
+    /// Captures log lines emitted on the current test's thread.
+    mod capture {
+        use log::{LevelFilter, Log, Metadata, Record};
+        use std::cell::RefCell;
+        use std::sync::Once;
+
+        thread_local! {
+            static LINES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+
+        struct CaptureLogger;
+
+        impl Log for CaptureLogger {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                LINES.with(|lines| lines.borrow_mut().push(record.args().to_string()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CaptureLogger = CaptureLogger;
+
+        pub fn start() {
+            static INIT: Once = Once::new();
+            INIT.call_once(|| {
+                log::set_logger(&LOGGER).unwrap();
+                log::set_max_level(LevelFilter::Debug);
+            });
+            LINES.with(|lines| lines.borrow_mut().clear());
+        }
+
+        pub fn lines() -> Vec<String> {
+            LINES.with(|lines| lines.borrow().clone())
+        }
+    }
+
+    #[test]
+    fn test_filter_log_lines_carry_filter_prefix() {
+        capture::start();
+        let client = MockClient::default()
+            .with_message(41, &raw_message("boss@tatari.tv", "me@tatari.tv", "review"))
+            .with_message(
+                42,
+                &raw_message("news@example.com", "me@tatari.tv", "weekly"),
+            )
+            .with_mailboxes(&["News"]);
+        let filters = vec![
+            MessageFilter {
+                star: Some(true),
+                ..from_filter("work", "*@tatari.tv")
+            },
+            MessageFilter {
+                move_to: Some("News".into()),
+                ..from_filter("news", "*@example.com")
+            },
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        imap_filter.execute().unwrap();
+
+        let lines = capture::lines();
+        let about = |uid: &str| {
+            lines
+                .iter()
+                .filter(|line| line.contains(uid))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert!(!about("UID 41").is_empty() && !about("UID 42").is_empty());
+        for line in about("UID 41") {
+            assert!(line.starts_with("[filter:work] "), "{}", line);
+        }
+        for line in about("UID 42") {
+            assert!(line.starts_with("[filter:news] "), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_copy_expunge_move_resumes_without_copying_twice() {
+        let capabilities = ["IMAP4REV1", "UIDPLUS"];
+        let client = MockClient::default()
+            .with_capabilities(&capabilities)
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "first"))
+            .with_mailboxes(&["Archive"])
+            .with_disconnect("UID EXPUNGE");
+        // The server kept the COPY and the \Deleted flag before the drop
+        let reconnector = Reconnector::new(MAX_RECONNECTS, move || {
+            let mut fresh = MockClient::default()
+                .with_capabilities(&capabilities)
+                .with_message(1, &raw_message("a@example.com", "me@example.com", "first"));
+            fresh.inbox[0].flags.push("\\Deleted".to_string());
+            Ok(fresh)
+        });
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        )
+        .with_reconnector(reconnector);
+        imap_filter.execute().unwrap();
+
+        assert!(imap_filter.client().calls_to("UID COPY").is_empty());
+        assert_eq!(
+            imap_filter.client().calls_to("UID EXPUNGE"),
+            vec!["UID EXPUNGE 1"]
+        );
+
+        // Nothing is left to do once the message is gone from the source
+        let mut client = MockClient::default();
+        let method = MoveMethod::CopyExpunge { uidplus: true };
+        method.resume(&mut client, 1, "Archive").unwrap();
+        assert_eq!(client.calls, vec!["UID FETCH 1 FLAGS"]);
+    }
+
+    #[test]
+    fn test_dropped_search_is_reconnected() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "first"))
+            .with_mailboxes(&["Archive"])
+            .with_disconnect("SEARCH");
+        let reconnector = Reconnector::new(MAX_RECONNECTS, || {
+            Ok(MockClient::default()
+                .with_message(1, &raw_message("a@example.com", "me@example.com", "first")))
+        });
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        )
+        .with_reconnector(reconnector);
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(summary.fetched, 1);
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec!["UID STORE 1 +X-GM-LABELS \"Archive\""]
+        );
+    }
+
+    #[test]
+    fn test_per_filter_counts() {
+        let client = MockClient::default()
+            .with_mailboxes(&["News"])
+            .with_message(
+                1,
+                &raw_message("news@example.com", "me@example.com", "weekly"),
+            )
+            .with_message(
+                2,
+                &raw_message("news@example.com", "me@example.com", "daily"),
+            )
+            .with_message(
+                3,
+                &raw_message("boss@tatari.tv", "me@example.com", "review"),
+            )
+            .with_message(
+                4,
+                &raw_message("friend@example.org", "me@example.com", "hi"),
+            )
+            .with_failure("UID STORE 2 ");
+        let filters = vec![
+            MessageFilter {
+                move_to: Some("News".into()),
+                ..from_filter("newsletters", "news@*")
+            },
+            MessageFilter {
+                star: Some(true),
+                ..from_filter("only-me", "boss@tatari.tv")
+            },
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            summary.filters,
+            vec![
+                FilterCounts {
+                    name: "newsletters".to_string(),
+                    matched: 2,
+                    moved: 1,
+                    failed: 1,
+                    ..Default::default()
+                },
+                FilterCounts {
+                    name: "only-me".to_string(),
+                    matched: 1,
+                    starred: 1,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(
+            summary.filters[0].to_string(),
+            "filter 'newsletters': matched 2, moved 1, failed 1"
+        );
+        assert_eq!(
+            summary.filters[1].to_string(),
+            "filter 'only-me': matched 1, starred 1"
+        );
+    }
+
+    #[test]
+    fn test_summary_exit_code() {
+        let summary = |applied, errors| Summary {
+            fetched: 5,
+            matched: 5,
+            applied,
+            errors,
+            ..Default::default()
+        };
+        assert_eq!(Summary::default().exit_code(), EXIT_NOTHING_TO_DO);
+        assert_eq!(summary(0, 0).exit_code(), EXIT_NOTHING_TO_DO);
+        assert_eq!(summary(3, 0).exit_code(), EXIT_ACTIONS_APPLIED);
+        assert_eq!(summary(3, 1).exit_code(), EXIT_ACTION_ERRORS);
+        assert_eq!(summary(0, 2).exit_code(), EXIT_ACTION_ERRORS);
+    }
+
+    #[test]
+    fn test_count_inbox_runs_no_filters() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "first"))
+            .with_message(2, &raw_message("b@example.com", "me@example.com", "second"));
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        );
+
+        assert_eq!(imap_filter.count_inbox().unwrap(), 2);
+        assert_eq!(imap_filter.client().calls, vec!["SELECT INBOX", "LOGOUT"]);
+    }
+
+    #[test]
+    fn test_sequence_sets() {
+        let ids: Vec<u32> = (1..=7).collect();
+        assert_eq!(sequence_sets(&ids, 3), vec!["1,2,3", "4,5,6", "7"]);
+        assert_eq!(sequence_sets(&ids, 7), vec!["1,2,3,4,5,6,7"]);
+        assert_eq!(sequence_sets(&ids[..2], 0), vec!["1", "2"]);
+        assert!(sequence_sets(&[], 500).is_empty());
+    }
+
+    #[test]
+    fn test_fetch_is_chunked() {
+        let mut client = MockClient::default();
+        for uid in 1..=5 {
+            client =
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "hi"));
+        }
+        let options = FilterOptions {
+            fetch_chunk_size: 2,
+            ..Default::default()
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![from_filter("all", "*")], options);
+        let summary = imap_filter.execute().unwrap();
+
+        let fetches: Vec<_> = imap_filter
+            .client()
+            .calls_to("FETCH")
+            .iter()
+            .map(|call| call.split(' ').nth(1).unwrap().to_string())
+            .collect();
+        assert_eq!(fetches, vec!["1,2", "3,4", "5"]);
+        assert_eq!(summary.fetched, 5);
+    }
+
+    #[test]
+    fn test_message_vanishing_between_search_and_fetch_is_skipped() {
+        let client = MockClient::default()
+            .with_message(
+                1,
+                &raw_message("a@example.com", "me@example.com", "still here"),
+            )
+            .with_mailboxes(&["Archive"])
+            .with_vanished(2);
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        );
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("FETCH"),
+            vec![format!("FETCH 1,2 {}", FETCH_ITEMS)]
+        );
+        assert_eq!(
+            (summary.fetched, summary.applied, summary.errors),
+            (1, 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_empty_mailbox_sends_no_fetch() {
+        let mut imap_filter = IMAPFilter::with_client(
+            MockClient::default(),
+            vec![move_filter("all", "Archive")],
+            FilterOptions::default(),
+        );
+        let summary = imap_filter.execute().unwrap();
+
+        assert!(imap_filter.client().calls_to("FETCH").is_empty());
+        assert_eq!(summary.exit_code(), EXIT_NOTHING_TO_DO);
+    }
+
+    #[test]
+    fn test_options_details_include_defaults() {
+        let options = FilterOptions {
+            limit: Some(25),
+            ..Default::default()
+        };
+        options.print_details();
+
+        let details = options.details();
+        assert!(
+            details.contains(&format!("inbox_query: {}", DEFAULT_INBOX_QUERY)),
+            "{:?}",
+            details
+        );
+        assert!(
+            details.contains(&format!("fetch_chunk_size: {}", DEFAULT_FETCH_CHUNK_SIZE)),
+            "{:?}",
+            details
+        );
+        assert!(
+            details.contains(&"create_folders: true".to_string()),
+            "{:?}",
+            details
+        );
+        assert!(details.contains(&"limit: 25".to_string()), "{:?}", details);
+        assert!(
+            !details.iter().any(|line| line.starts_with("gmail_search")),
+            "{:?}",
+            details
+        );
+    }
+
+    #[test]
+    fn test_action_spikes() {
+        let previous = BTreeMap::from([
+            ("newsletters".to_string(), 10),
+            ("receipts".to_string(), 0),
+            ("steady".to_string(), 3),
+        ]);
+        let current = BTreeMap::from([
+            ("newsletters".to_string(), 51),
+            ("receipts".to_string(), 6),
+            ("steady".to_string(), 15),
+            ("brand-new".to_string(), 400),
+        ]);
+
+        let spikes = action_spikes(&previous, &current, 5.0);
+        assert_eq!(
+            spikes,
+            vec![
+                ActionSpike {
+                    filter: "newsletters".to_string(),
+                    previous: 10,
+                    current: 51
+                },
+                ActionSpike {
+                    filter: "receipts".to_string(),
+                    previous: 0,
+                    current: 6
+                },
+            ]
+        );
+        assert!(
+            action_spikes(&previous, &current, 10.0).is_empty(),
+            "a higher factor tolerates more"
+        );
+    }
+
+    #[test]
+    fn test_summary_action_counts_sum_across_mailboxes() {
+        let summary = Summary {
+            filters: vec![
+                FilterCounts {
+                    name: "archive".to_string(),
+                    moved: 2,
+                    failed: 1,
+                    ..Default::default()
+                },
+                FilterCounts {
+                    name: "star".to_string(),
+                    starred: 4,
+                    ..Default::default()
+                },
+                FilterCounts {
+                    name: "archive".to_string(),
+                    moved: 3,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            summary.action_counts(),
+            BTreeMap::from([("archive".to_string(), 6), ("star".to_string(), 4)])
+        );
+    }
+
+    #[test]
+    fn test_limit_ids() {
+        let ids: HashSet<u32> = [5, 3, 9, 1].into_iter().collect();
+        assert_eq!(limit_ids(ids.clone(), None), vec![1, 3, 5, 9]);
+        assert_eq!(limit_ids(ids.clone(), Some(2)), vec![1, 3]);
+        assert_eq!(limit_ids(ids, Some(10)), vec![1, 3, 5, 9]);
+    }
+
+    #[test]
+    fn test_limit_is_shared_across_mailboxes() {
+        let client = (1..=3).fold(
+            MockClient::default().with_mailboxes(&["Archive", "Spam review"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let filters = vec![
+            MessageFilter {
+                mailbox: Some("Spam review".to_string()),
+                ..move_filter("spam", "Archive")
+            },
+            move_filter("inbox", "Archive"),
+        ];
+        let options = FilterOptions {
+            limit: Some(2),
+            ..Default::default()
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, options);
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!((summary.fetched, summary.applied), (2, 2));
+        assert_eq!(
+            imap_filter.client().calls_to("SELECT"),
+            vec!["SELECT Spam review"]
+        );
+
+        let client = (1..=3).fold(
+            MockClient::default().with_mailboxes(&["Archive", "Spam review"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let filters = vec![
+            MessageFilter {
+                mailbox: Some("Spam review".to_string()),
+                ..move_filter("spam", "Archive")
+            },
+            move_filter("inbox", "Archive"),
+        ];
+        let options = FilterOptions {
+            limit: Some(5),
+            ..Default::default()
+        };
+        let mut imap_filter = IMAPFilter::with_client(client, filters, options);
+        let summary = imap_filter.execute().unwrap();
+        assert_eq!(
+            summary.fetched, 5,
+            "3 from the first mailbox, 2 from the second"
+        );
+    }
+
+    #[test]
+    fn test_limit_truncates_processed_messages() {
+        let client = (1..=5).fold(
+            MockClient::default().with_mailboxes(&["Archive"]),
+            |client, uid| {
+                client.with_message(uid, &raw_message("a@example.com", "me@example.com", "bulk"))
+            },
+        );
+        let options = FilterOptions {
+            limit: Some(2),
+            ..Default::default()
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![move_filter("all", "Archive")], options);
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(
+            (
+                summary.fetched,
+                summary.matched,
+                summary.applied,
+                summary.errors
+            ),
+            (2, 2, 2, 0)
+        );
+        assert_eq!(
+            summary
+                .actions
+                .iter()
+                .map(|record| record.uid)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            imap_filter.client().calls_to("FETCH"),
+            vec!["FETCH 1,2 (UID FLAGS BODY.PEEK[] RFC822.SIZE INTERNALDATE)"]
+        );
+        assert_eq!(imap_filter.client().calls_to("UID STORE").len(), 2);
+    }
+
+    #[test]
+    fn test_translate_folder_path() {
+        assert_eq!(translate_folder_path("Archive/2024", "."), "Archive.2024");
+        assert_eq!(
+            translate_folder_path("Clients/Acme Corp/Invoices", "\\"),
+            "Clients\\Acme Corp\\Invoices"
+        );
+        assert_eq!(translate_folder_path("Archive/2024", "/"), "Archive/2024");
+        assert_eq!(translate_folder_path("Work", "."), "Work");
+    }
+
+    #[test]
+    fn test_move_targets_use_server_delimiter() {
+        let client = MockClient::default()
+            .with_message(1, &raw_message("a@example.com", "me@example.com", "old"))
+            .with_delimiter(".");
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![move_filter("archive", "Archive/2024")],
+            FilterOptions::default(),
+        );
+        imap_filter.execute().unwrap();
+
+        let client = imap_filter.client();
+        assert_eq!(client.calls_to("CREATE"), vec!["CREATE Archive.2024"]);
+        assert_eq!(
+            client.calls_to("UID STORE"),
+            vec!["UID STORE 1 +X-GM-LABELS \"Archive.2024\""]
+        );
+    }
+
+    #[test]
+    fn test_never_touch_sender_survives_move_filter() {
+        let client = MockClient::default()
+            .with_message(
+                1,
+                &raw_message("ceo@mycompany.com", "me@mycompany.com", "board"),
+            )
+            .with_message(
+                2,
+                &raw_message("promo@shop.com", "me@mycompany.com", "sale"),
+            )
+            .with_mailboxes(&["Archive"]);
+        let options = FilterOptions {
+            never_touch: AddressFilter::new(vec!["*@mycompany.com".to_string()]).unwrap(),
+            ..Default::default()
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![move_filter("all", "Archive")], options);
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec!["UID STORE 2 +X-GM-LABELS \"Archive\""]
+        );
+    }
+
+    #[test]
+    fn test_dedupe_by_message_id_acts_on_first_copy_only() {
+        let raw =
+            "Message-ID: <abc@example.com>\r\nFrom: a@example.com\r\nSubject: dup\r\n\r\nbody\r\n";
+        let client = MockClient::default()
+            .with_message(1, raw)
+            .with_message(2, raw)
+            .with_message(3, &raw_message("b@example.com", "me@example.com", "unique"))
+            .with_mailboxes(&["Archive"]);
+        let options = FilterOptions {
+            dedupe_by_message_id: true,
+            ..Default::default()
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![move_filter("all", "Archive")], options);
+        let summary = imap_filter.execute().unwrap();
+
+        assert_eq!(summary.matched, 2);
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec![
+                "UID STORE 1 +X-GM-LABELS \"Archive\"",
+                "UID STORE 3 +X-GM-LABELS \"Archive\""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_move_target_is_created_once() {
+        let client = MockClient::default().with_mailboxes(&["INBOX", "Archive"]);
+        let filters = vec![
+            move_filter("a", "Archive"),
+            move_filter("b", "Work"),
+            move_filter("c", "Work"),
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        imap_filter.execute().unwrap();
+
+        assert_eq!(imap_filter.client().calls_to("CREATE"), vec!["CREATE Work"]);
+    }
+
+    #[test]
+    fn test_missing_folders_are_all_reported() {
+        let existing: HashSet<String> = ["Archive".to_string()].into();
+        assert_eq!(
+            missing_folders(["Archive", "Wrok", "inbox", "Recipts"], &existing),
+            vec!["Wrok", "Recipts"]
+        );
+        assert!(missing_folders(["Archive", "INBOX"], &existing).is_empty());
+    }
+
+    #[test]
+    fn test_missing_source_mailboxes_fail_before_selecting() {
+        let client = MockClient::default().with_mailboxes(&["Archive"]);
+        let from = |mailbox: &str| MessageFilter {
+            mailbox: Some(mailbox.to_string()),
+            ..move_filter(mailbox, "Archive")
+        };
+
+        let mut imap_filter = IMAPFilter::with_client(
+            client,
+            vec![from("Spam review"), from("Newsletters"), from("Archive")],
+            FilterOptions::default(),
+        );
+        let err = imap_filter.execute().unwrap_err();
+
+        assert!(matches!(err, FilterError::Config(_)), "{:?}", err);
+        assert!(
+            err.to_string().contains("\"Newsletters\", \"Spam review\""),
+            "{}",
+            err
+        );
+        assert!(imap_filter.client().calls_to("SELECT").is_empty());
+        assert!(imap_filter.client().calls_to("CREATE").is_empty());
+    }
+
+    #[test]
+    fn test_missing_move_target_not_created_when_disabled() {
+        let client = MockClient::default().with_mailboxes(&["INBOX"]);
+        let options = FilterOptions {
+            create_folders: false,
+            ..Default::default()
+        };
+
+        let mut imap_filter =
+            IMAPFilter::with_client(client, vec![move_filter("b", "Work")], options);
+        imap_filter.execute().unwrap();
+
+        assert!(imap_filter.client().calls_to("CREATE").is_empty());
+    }
+
+    #[test]
+    fn test_execute_all_match_applies_each_filter() {
+        let client = MockClient::default().with_message(
+            7,
+            &raw_message("scott.idler@tatari.tv", "team@tatari.tv", "notes"),
+        );
+        let filters = vec![
+            MessageFilter {
+                move_to: Some("Company".into()),
+                ..from_filter("company", "*@tatari.tv")
+            },
+            MessageFilter {
+                move_to: Some("Mine".into()),
+                ..from_filter("me", "scott.idler@tatari.tv")
+            },
+        ];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, all_match());
+        imap_filter.execute().unwrap();
+
+        assert_eq!(
+            imap_filter.client().calls_to("UID STORE"),
+            vec![
+                "UID STORE 7 +X-GM-LABELS \"Company\"",
+                "UID STORE 7 +X-GM-LABELS \"Mine\""
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gmail_star_is_verified_through_labels() {
+        let client = MockClient::default()
+            .with_message(2, &raw_message("boss@tatari.tv", "me@tatari.tv", "new"));
+        let filters = vec![MessageFilter {
+            star: Some(true),
+            ..from_filter("boss", "boss@tatari.tv")
+        }];
+
+        let mut imap_filter = IMAPFilter::with_client(client, filters, FilterOptions::default());
+        imap_filter.execute().unwrap();
+
+        let client = &mut imap_filter.client;
+        assert_eq!(
+            client.calls_to("UID FETCH 2"),
+            vec!["UID FETCH 2 (UID X-GM-LABELS)"]
+        );
+        assert!(client.inbox[0].flags.is_empty(), "labels are not FLAGS");
+        assert_eq!(
+            StarMethod::GmailLabel.read_back(client, 2).unwrap(),
+            vec!["\\Starred"]
+        );
+        assert!(StarMethod::Flagged.read_back(client, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_filter_strategy_deserialize() {
+        assert_eq!(
+            serde_yaml::from_str::<FilterStrategy>("first_match").unwrap(),
+            FilterStrategy::FirstMatch
+        );
+        assert_eq!(
+            serde_yaml::from_str::<FilterStrategy>("all_match").unwrap(),
+            FilterStrategy::AllMatch
+        );
     }
 }
@@ -0,0 +1,74 @@
+/// Quotes a Gmail label (or mailbox name) as an IMAP quoted string, so names
+/// with spaces, quotes or backslashes survive, e.g. `Clients/Acme Corp`.
+pub fn quote_label(label: &str) -> String {
+    format!("\"{}\"", label.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Parses a label list as sent in STORE/FETCH X-GM-LABELS: a single atom or
+/// quoted string, or a parenthesized list of them. Quoted strings may contain
+/// spaces and escaped quotes; system labels like `\Starred` are atoms.
+pub fn parse_labels(list: &str) -> Vec<String> {
+    let list = list.trim();
+    let inner = list
+        .strip_prefix('(')
+        .and_then(|rest| rest.strip_suffix(')'))
+        .unwrap_or(list);
+
+    let mut labels = Vec::new();
+    let mut chars = inner.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut label = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => label.extend(chars.next()),
+                    '"' => break,
+                    _ => label.push(c),
+                }
+            }
+            labels.push(label);
+        } else {
+            let mut label = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                label.push(c);
+                chars.next();
+            }
+            labels.push(label);
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_label_escapes() {
+        assert_eq!(quote_label("Team Updates"), "\"Team Updates\"");
+        assert_eq!(quote_label("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote_label("back\\slash"), "\"back\\\\slash\"");
+    }
+
+    #[test]
+    fn test_parse_labels_round_trips_quoted_names() {
+        for label in [
+            "Team Updates",
+            "Clients/Acme Corp",
+            "say \"hi\"",
+            "back\\slash",
+        ] {
+            assert_eq!(parse_labels(&quote_label(label)), vec![label.to_string()]);
+        }
+        assert_eq!(
+            parse_labels("(\\Starred \"Team Updates\" Work)"),
+            vec!["\\Starred", "Team Updates", "Work"]
+        );
+    }
+}
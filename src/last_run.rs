@@ -0,0 +1,146 @@
+use crate::error::{FilterError, Result};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Where an account's last-run timestamp lives: next to the first config
+/// file, or in the working directory when the config came from stdin.
+pub fn last_run_path(config_paths: &[PathBuf], account: &str) -> PathBuf {
+    let dir = config_paths
+        .iter()
+        .find(|path| path.as_path() != Path::new("-"))
+        .and_then(|path| path.parent())
+        .unwrap_or(Path::new(""));
+    dir.join(format!(".imap-filter-{}.last-run", account))
+}
+
+/// Reads the RFC 3339 timestamp at `path`; `None` when there is no file yet.
+pub fn read_last_run(path: &Path) -> Result<Option<DateTime<Utc>>> {
+    match fs::read_to_string(path) {
+        Ok(content) => DateTime::parse_from_rfc3339(content.trim())
+            .map(|timestamp| Some(timestamp.with_timezone(&Utc)))
+            .map_err(|e| {
+                FilterError::Io(format!("Invalid timestamp in {}: {}", path.display(), e))
+            }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(FilterError::Io(format!(
+            "Failed to read {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// The `SINCE` date that covers everything after `last_run`. IMAP compares
+/// against INTERNALDATE in the server's own timezone, which can be up to a
+/// day behind UTC, so the UTC date alone would skip mail that arrived late
+/// in the server's previous day.
+pub fn since_date(last_run: DateTime<Utc>) -> NaiveDate {
+    (last_run - TimeDelta::days(1)).date_naive()
+}
+
+pub fn write_last_run(path: &Path, timestamp: DateTime<Utc>) -> Result<()> {
+    fs::write(path, format!("{}\n", timestamp.to_rfc3339()))
+        .map_err(|e| FilterError::Io(format!("Failed to write {}: {}", path.display(), e)))
+}
+
+/// Reads the per-filter action counts saved at `path` as JSON; `None` when
+/// there is no file yet.
+pub fn read_action_counts(path: &Path) -> Result<Option<BTreeMap<String, usize>>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).map(Some).map_err(|e| {
+            FilterError::Io(format!(
+                "Invalid action counts in {}: {}",
+                path.display(),
+                e
+            ))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(FilterError::Io(format!(
+            "Failed to read {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+pub fn write_action_counts(path: &Path, counts: &BTreeMap<String, usize>) -> Result<()> {
+    let json = serde_json::to_string_pretty(counts)
+        .map_err(|e| FilterError::Io(format!("Failed to encode action counts: {}", e)))?;
+    fs::write(path, json + "\n")
+        .map_err(|e| FilterError::Io(format!("Failed to write {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_run_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("imap-filter-last-run-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(
+            read_last_run(&path).unwrap(),
+            None,
+            "first run has no timestamp"
+        );
+
+        let timestamp = DateTime::parse_from_rfc3339("2025-03-07T09:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        write_last_run(&path, timestamp).unwrap();
+        assert_eq!(read_last_run(&path).unwrap(), Some(timestamp));
+
+        fs::write(&path, "yesterday").unwrap();
+        assert!(read_last_run(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_action_counts_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("imap-filter-action-counts-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(read_action_counts(&path).unwrap(), None);
+
+        let counts = BTreeMap::from([("newsletters".to_string(), 12), ("receipts".to_string(), 0)]);
+        write_action_counts(&path, &counts).unwrap();
+        assert_eq!(read_action_counts(&path).unwrap(), Some(counts));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_since_date_covers_servers_west_of_utc() {
+        let last_run = DateTime::parse_from_rfc3339("2025-03-07T02:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        // Arrived after the last run, but on 6 March in a UTC-5 server's local time
+        let arrived = DateTime::parse_from_rfc3339("2025-03-06T21:30:00-05:00").unwrap();
+        assert!(arrived > last_run);
+
+        assert_eq!(
+            since_date(last_run),
+            NaiveDate::from_ymd_opt(2025, 3, 6).unwrap()
+        );
+        assert!(arrived.date_naive() >= since_date(last_run));
+    }
+
+    #[test]
+    fn test_last_run_path() {
+        let paths = [
+            PathBuf::from("-"),
+            PathBuf::from("/etc/imap-filter/work.yml"),
+        ];
+        assert_eq!(
+            last_run_path(&paths, "work"),
+            PathBuf::from("/etc/imap-filter/.imap-filter-work.last-run")
+        );
+        assert_eq!(
+            last_run_path(&[PathBuf::from("-")], "default"),
+            PathBuf::from(".imap-filter-default.last-run")
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Filter an IMAP mailbox with rules loaded from a YAML config.
+//!
+//! The `imap-filter` binary is a thin wrapper over [`run`]; embedders can
+//! load a [`Config`] themselves (or build one in code) and call it directly.
+
+use error::Result;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+pub mod address_filter;
+pub mod audit;
+pub mod client;
+pub mod config;
+pub mod connection;
+pub mod credentials;
+pub mod error;
+pub mod imap_filter;
+pub mod labels;
+pub mod last_run;
+pub mod log_style;
+pub mod message;
+pub mod message_filter;
+pub mod query;
+pub mod report;
+pub mod subject_filter;
+
+pub use config::{load_config, load_configs, Config};
+pub use credentials::Credentials;
+pub use error::FilterError;
+pub use imap_filter::{FilterCounts, IMAPFilter, Summary};
+
+/// Connects with `creds`, applies every filter in `config`, and logs out.
+pub fn run(config: Config, creds: Credentials) -> Result<Summary> {
+    run_until(config, creds, Arc::default())
+}
+
+/// Like [`run`], but stops cleanly before the next message once `stop` is set.
+pub fn run_until(mut config: Config, creds: Credentials, stop: Arc<AtomicBool>) -> Result<Summary> {
+    if let Some(expected) = &config.confirm_account {
+        credentials::confirm_account(expected, &creds.username)?;
+    }
+    config.append_default_filter();
+    let settings = config.connection_settings();
+    let options = config.filter_options();
+    let audit = config
+        .audit_log
+        .as_deref()
+        .map(audit::AuditLog::open)
+        .transpose()?;
+    let previous_counts = config
+        .action_counts
+        .as_deref()
+        .map(last_run::read_action_counts)
+        .transpose()?
+        .flatten();
+    let mut imap_filter = IMAPFilter::new(
+        creds,
+        config.filters,
+        options,
+        settings,
+        config.protocol_debug,
+    )?
+    .with_stop_flag(stop);
+    if let Some(audit) = audit {
+        imap_filter = imap_filter.with_audit_log(audit);
+    }
+    if let Some(previous) = previous_counts {
+        imap_filter = imap_filter.with_previous_counts(previous);
+    }
+    let summary = imap_filter.execute()?;
+    // A partial run's counts would make the next full one look like a spike
+    if let (Some(path), false) = (&config.action_counts, summary.interrupted) {
+        last_run::write_action_counts(path, &summary.action_counts())?;
+    }
+    Ok(summary)
+}
+
+/// Connects with `creds`, selects INBOX, and logs out without running any
+/// filters. Returns the INBOX message count.
+pub fn healthcheck(config: Config, creds: Credentials) -> Result<u32> {
+    if let Some(expected) = &config.confirm_account {
+        credentials::confirm_account(expected, &creds.username)?;
+    }
+    let settings = config.connection_settings();
+    let options = config.filter_options();
+    IMAPFilter::new(creds, Vec::new(), options, settings, config.protocol_debug)?.count_inbox()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A scripted in-memory IMAP server that accepts any login and reports three
+    /// messages in INBOX, none of which any search matches.
+    fn fake_imap_server() -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            socket.write_all(b"* OK fake IMAP ready\r\n").unwrap();
+
+            let mut commands = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let mut parts = line.split_whitespace();
+                let tag = parts.next().unwrap_or("*").to_string();
+                let command = parts.next().unwrap_or("").to_uppercase();
+                let untagged = match command.as_str() {
+                    "SELECT" => "* 3 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen)\r\n",
+                    "CAPABILITY" => "* CAPABILITY IMAP4rev1 X-GM-EXT-1\r\n",
+                    "SEARCH" => "* SEARCH\r\n",
+                    "LOGOUT" => "* BYE logging out\r\n",
+                    _ => "",
+                };
+                write!(socket, "{}{} OK {} completed\r\n", untagged, tag, command).unwrap();
+                commands.push(command.clone());
+                line.clear();
+                if command == "LOGOUT" {
+                    break;
+                }
+            }
+            commands
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_run_against_fake_server() {
+        let (port, server) = fake_imap_server();
+        let config: Config = serde_yaml::from_str(&format!(
+            "security: plain\nport: {}\nfilters:\n- only-me:\n    to: ['me@example.com']\n",
+            port
+        ))
+        .unwrap();
+        let creds = Credentials {
+            domain: "127.0.0.1".to_string(),
+            username: "me@example.com".to_string(),
+            password: "secret".to_string(),
+        };
+
+        let summary = run(config, creds).unwrap();
+
+        assert_eq!(
+            (
+                summary.fetched,
+                summary.matched,
+                summary.applied,
+                summary.errors
+            ),
+            (0, 0, 0, 0)
+        );
+        assert_eq!(summary.filters[0].name, "only-me");
+        let commands = server.join().unwrap();
+        assert_eq!(commands.first().map(String::as_str), Some("LOGIN"));
+        assert_eq!(commands.last().map(String::as_str), Some("LOGOUT"));
+    }
+
+    #[test]
+    fn test_healthcheck_against_fake_server() {
+        let (port, server) = fake_imap_server();
+        let config: Config =
+            serde_yaml::from_str(&format!("security: plain\nport: {}\nfilters: []\n", port))
+                .unwrap();
+        let creds = Credentials {
+            domain: "127.0.0.1".to_string(),
+            username: "me@example.com".to_string(),
+            password: "secret".to_string(),
+        };
+
+        assert_eq!(healthcheck(config, creds).unwrap(), 3);
+        assert_eq!(server.join().unwrap(), vec!["LOGIN", "SELECT", "LOGOUT"]);
+    }
+
+    #[test]
+    fn test_confirm_account_mismatch_never_connects() {
+        // Nothing listens on port 1; the check must fail before any connection attempt
+        let config: Config =
+            serde_yaml::from_str("security: plain\nport: 1\nconfirm_account: me@example.com\n")
+                .unwrap();
+        let creds = Credentials {
+            domain: "127.0.0.1".to_string(),
+            username: "stale@example.com".to_string(),
+            password: "secret".to_string(),
+        };
+
+        let err = run(config, creds).unwrap_err();
+        assert!(matches!(err, FilterError::Config(_)), "{:?}", err);
+    }
+}
@@ -0,0 +1,75 @@
+use serde::Deserialize;
+
+/// How status markers are rendered in log lines.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStyle {
+    #[default]
+    Emoji,
+    /// ASCII-only markers for log aggregators and grep.
+    Plain,
+}
+
+/// The kinds of outcome a log line can be tagged with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    Move,
+    Star,
+    Failure,
+}
+
+impl Marker {
+    pub const ALL: [Marker; 3] = [Marker::Move, Marker::Star, Marker::Failure];
+}
+
+impl LogStyle {
+    pub fn marker(self, marker: Marker) -> &'static str {
+        match (self, marker) {
+            (LogStyle::Emoji, Marker::Move) => "✅",
+            (LogStyle::Emoji, Marker::Star) => "⭐",
+            (LogStyle::Emoji, Marker::Failure) => "❌",
+            (LogStyle::Plain, Marker::Move) => "[MOVE]",
+            (LogStyle::Plain, Marker::Star) => "[STAR]",
+            (LogStyle::Plain, Marker::Failure) => "[FAIL]",
+        }
+    }
+}
+
+/// The `[kind:name]` prefix for log lines emitted on behalf of one filter,
+/// so logs can be grepped or aggregated per rule.
+pub fn scope(kind: &str, name: &str) -> String {
+    format!("[{}:{}]", kind, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_markers_are_ascii() {
+        for marker in Marker::ALL {
+            let line = format!(
+                "{} Successfully labeled UID 1 with 'Archive'",
+                LogStyle::Plain.marker(marker)
+            );
+            assert!(line.is_ascii(), "non-ASCII output: {}", line);
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(scope("filter", "only-me"), "[filter:only-me]");
+    }
+
+    #[test]
+    fn test_log_style_deserialize() {
+        assert_eq!(
+            serde_yaml::from_str::<LogStyle>("emoji").unwrap(),
+            LogStyle::Emoji
+        );
+        assert_eq!(
+            serde_yaml::from_str::<LogStyle>("plain").unwrap(),
+            LogStyle::Plain
+        );
+    }
+}
@@ -1,76 +1,201 @@
 #![allow(dead_code, unused_imports)]
 
-use clap::{Parser};
+use clap::error::ErrorKind;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
 use env_logger::Builder;
-use eyre::{Result, eyre};
-use log::{debug, info, error};
+use eyre::{eyre, Result};
+use log::{debug, error, info, LevelFilter};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
 use std::path::PathBuf;
-use std::io::Write;
-use std::fs;
-use std::fs::OpenOptions;
-use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-mod message;
-mod message_filter;
-mod address_filter;
-mod imap_filter;
+use imap_filter::credentials::{redact, resolve_password, PasswordSources};
+use imap_filter::imap_filter::{EXIT_FATAL, EXIT_NOTHING_TO_DO, EXIT_TIMED_OUT};
+use imap_filter::last_run::{last_run_path, read_last_run, since_date, write_last_run};
+use imap_filter::log_style::LogStyle;
+use imap_filter::message_filter::{parse_age, select_filters};
+use imap_filter::report::{write_report, OutputFormat};
+use imap_filter::{load_configs, Config, Credentials, FilterError, Summary};
 
-use imap_filter::{IMAPFilter, MessageFilter};
-
-#[derive(Parser, Debug)]
-#[command(name = "imap-filter", version = env!("GIT_DESCRIBE"), about = "IMAP email filtering CLI", long_about = None)]
+#[derive(Parser)]
+#[command(
+    name = "imap-filter",
+    version = env!("GIT_DESCRIBE"),
+    about = "IMAP email filtering CLI",
+    long_about = None,
+    after_help = "Exit codes: 0 nothing to do, 10 actions applied, 20 some actions failed, 30 --max-runtime reached, 1 fatal error"
+)]
 struct Cli {
-    #[arg(short, long, default_value = "imap-filter.yml")]
-    config: PathBuf,
+    /// Config file(s), merged in order; `-` reads from stdin
+    #[arg(short, long, global = true, default_value = "imap-filter.yml")]
+    config: Vec<PathBuf>,
 
-    #[arg(short = 'd', long, env = "IMAP_DOMAIN")]
+    #[arg(short = 'd', long, global = true, env = "IMAP_DOMAIN")]
     imap_domain: Option<String>,
 
-    #[arg(short = 'u', long, env = "IMAP_USERNAME")]
+    #[arg(short = 'u', long, global = true, env = "IMAP_USERNAME")]
     imap_username: Option<String>,
 
-    #[arg(short = 'p', long, env = "IMAP_PASSWORD")]
+    /// IMAP password (falls back to --password-command, --password-file, $IMAP_PASSWORD, then the config)
+    #[arg(short = 'p', long, global = true)]
     imap_password: Option<String>,
+
+    /// Shell command whose stdout is used as the IMAP password
+    #[arg(long, global = true)]
+    password_command: Option<String>,
+
+    /// File whose contents are used as the IMAP password
+    #[arg(long, global = true)]
+    password_file: Option<PathBuf>,
+
+    /// Use only the named account; uses every account when omitted
+    #[arg(long, global = true, value_name = "NAME")]
+    account: Option<String>,
+
+    /// Log the raw IMAP protocol exchange (after login) to stderr
+    #[arg(long, global = true)]
+    protocol_debug: bool,
+
+    /// Also log to stderr; repeat (-vv) for debug logs
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Options for `run`, also accepted without the subcommand
+    #[command(flatten)]
+    run: RunArgs,
 }
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    imap_domain: Option<String>,
-    imap_username: Option<String>,
-    imap_password: Option<String>,
-    filters: Vec<HashMap<String, MessageFilter>>,
-    folders: Option<HashMap<String, FolderSettings>>,
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+enum Command {
+    /// Apply the filters (the default when no subcommand is given)
+    Run(RunArgs),
+    /// Connect, log in, select INBOX, print its message count, and log out
+    Healthcheck,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Args)]
+struct RunArgs {
+    /// Run only the named filter (repeatable); runs every filter when omitted
+    #[arg(long = "only-filter", value_name = "NAME")]
+    only_filters: Vec<String>,
+
+    /// Do not create missing Move target folders; only warn about them
+    #[arg(long)]
+    no_create_folders: bool,
+
+    /// Stop with an error on the first failed move/star instead of continuing
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Process at most N messages this run
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Use plain ASCII markers like [MOVE] instead of emoji in log lines
+    #[arg(long)]
+    no_emoji: bool,
+
+    /// Only fetch messages since the previous successful run (full scan on the first run)
+    #[arg(long)]
+    since_last_run: bool,
+
+    /// Write a per-message action report to this file
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Format of the --report file
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    output_format: OutputFormat,
+
+    /// Stop starting new actions after this long (e.g. `90s`, `10m`), log out, and exit 30
+    #[arg(long, value_name = "DURATION", value_parser = parse_runtime)]
+    max_runtime: Option<Duration>,
+
+    /// Log the headers, parsed fields and every filter's verdict for this UID
+    #[arg(long, value_name = "UID")]
+    debug_uid: Option<u32>,
+
+    /// Print each account's resolved filters and options, then exit without connecting
+    #[arg(long)]
+    explain: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct FolderSettings {
-    read: Option<String>,
-    unread: Option<String>,
+impl Cli {
+    /// The subcommand to run; `run` with the top-level options when none was
+    /// given. Run options placed before a subcommand are rejected rather
+    /// than silently dropped.
+    fn subcommand(&self) -> Result<Command, clap::Error> {
+        match &self.command {
+            None => Ok(Command::Run(self.run.clone())),
+            Some(_) if self.run != RunArgs::default() => Err(Cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "options for `run` must come after the subcommand, e.g. `imap-filter run --limit 5`",
+            )),
+            Some(command) => Ok(command.clone()),
+        }
+    }
 }
 
-fn load_config(cli: &Cli) -> Result<Config> {
-    debug!("Loading configuration from {:?}", cli.config);
+impl fmt::Debug for Cli {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cli")
+            .field("config", &self.config)
+            .field("imap_domain", &self.imap_domain)
+            .field("imap_username", &self.imap_username)
+            .field("imap_password", &redact(&self.imap_password))
+            .field("password_command", &redact(&self.password_command))
+            .field("password_file", &self.password_file)
+            .field("account", &self.account)
+            .field("protocol_debug", &self.protocol_debug)
+            .field("verbose", &self.verbose)
+            .field("command", &self.command)
+            .field("run", &self.run)
+            .finish()
+    }
+}
 
-    let content = fs::read_to_string(&cli.config)
-        .map_err(|e| {
-            error!("Failed to read config file {}: {}", cli.config.display(), e);
-            eyre!("Failed to read config file {}: {}", cli.config.display(), e)
-        })?;
+/// Writes log lines to the log file and, when `stderr` is set, to stderr too.
+struct Tee {
+    file: File,
+    stderr: bool,
+}
 
-    let config: Config = serde_yaml::from_str(&content)
-        .map_err(|e| {
-            error!("Failed to parse YAML: {}", e);
-            eyre!("Failed to parse YAML: {}", e)
-        })?;
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write_all(buf)?;
+        if self.stderr {
+            io::stderr().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
 
-    debug!("Successfully loaded configuration.");
-    debug!("Parsed config: {:?}", config);
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.stderr {
+            io::stderr().flush()?;
+        }
+        Ok(())
+    }
+}
 
-    Ok(config)
+/// The level forced by `-v` flags; `None` leaves it to `RUST_LOG` (default info).
+fn verbosity_level(verbose: u8) -> Option<LevelFilter> {
+    match verbose {
+        0 => None,
+        1 => Some(LevelFilter::Info),
+        2 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    }
 }
 
-fn setup_logging() {
+fn setup_logging(verbose: u8) {
     let log_file = "imap-filter.log";
     let file = OpenOptions::new()
         .create(true)
@@ -78,9 +203,16 @@ fn setup_logging() {
         .open(log_file)
         .expect("Failed to open log file");
 
-    let log_writer = Box::new(file);
+    let log_writer = Box::new(Tee {
+        file,
+        stderr: verbose > 0,
+    });
 
-    Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+    let mut builder = Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(level) = verbosity_level(verbose) {
+        builder.filter_level(level);
+    }
+    builder
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -94,53 +226,324 @@ fn setup_logging() {
         .init();
 }
 
-fn main() -> Result<()> {
-    setup_logging();
-    info!("=====================================================================================================================");
-    info!("Starting IMAP Filter");
-
-    let cli = Cli::parse();
-    debug!("Parsed CLI arguments: {:?}", cli);
-
-    let config = load_config(&cli)?;
-
-    let imap_domain = cli.imap_domain.or(config.imap_domain)
+/// Resolves one account's credentials from the CLI, the environment, and its config.
+fn account_credentials(cli: &Cli, config: &mut Config) -> Result<Credentials> {
+    let domain = cli
+        .imap_domain
+        .clone()
+        .or(config.imap_domain.take())
         .ok_or_else(|| {
             error!("IMAP domain is required but missing.");
             eyre!("IMAP domain is required")
         })?;
 
-    let imap_username = cli.imap_username.or(config.imap_username)
+    let username = cli
+        .imap_username
+        .clone()
+        .or(config.imap_username.take())
         .ok_or_else(|| {
             error!("IMAP username is required but missing.");
             eyre!("IMAP username is required")
         })?;
 
-    let imap_password = cli.imap_password.or(config.imap_password)
-        .ok_or_else(|| {
-            error!("IMAP password is required but missing.");
-            eyre!("IMAP password is required")
-        })?;
+    let password = resolve_password(PasswordSources {
+        flag: cli.imap_password.clone(),
+        command: cli
+            .password_command
+            .clone()
+            .or(config.imap_password_command.take()),
+        file: cli
+            .password_file
+            .clone()
+            .or(config.imap_password_file.take()),
+        env: std::env::var("IMAP_PASSWORD").ok(),
+        config: config.imap_password.take(),
+    })
+    .inspect_err(|e| error!("Failed to resolve IMAP password: {}", e))?;
 
     debug!("IMAP connection parameters retrieved successfully.");
+    config.protocol_debug |= cli.protocol_debug;
+    Ok(Credentials {
+        domain,
+        username,
+        password,
+    })
+}
 
-    let filters: Vec<MessageFilter> = config
-        .filters
-        .into_iter()
-        .flat_map(|map| {
-            map.into_iter().map(|(name, mut filter)| {
-                filter.name = name;
-                filter
-            })
-        })
-        .collect();
+/// Applies the `run` options that override the config.
+fn apply_run_args(args: &RunArgs, config: &mut Config) -> Result<()> {
+    config.filters = select_filters(std::mem::take(&mut config.filters), &args.only_filters)?;
+    config.create_folders &= !args.no_create_folders;
+    config.fail_fast |= args.fail_fast;
+    config.limit = args.limit.or(config.limit);
+    config.debug_uid = args.debug_uid;
+    if args.no_emoji {
+        config.log_style = LogStyle::Plain;
+    }
+    Ok(())
+}
 
-    debug!("Loaded {} filters.", filters.len());
-    debug!("Filters: {:?}", filters);
+fn parse_runtime(value: &str) -> Result<Duration, FilterError> {
+    parse_age(value)?
+        .to_std()
+        .map_err(|_| FilterError::InvalidDuration(format!("Invalid runtime '{}'", value)))
+}
 
-    let mut imap_filter = IMAPFilter::new(imap_domain, imap_username, imap_password, filters)?;
-    imap_filter.execute()?;
+/// Resolves one account's credentials, applies the CLI overrides, and runs
+/// it with whatever is left of the `--max-runtime` budget.
+fn run_account(
+    cli: &Cli,
+    args: &RunArgs,
+    name: &str,
+    mut config: Config,
+    stop: &Arc<AtomicBool>,
+    remaining: Option<Duration>,
+) -> Result<Summary> {
+    let creds = account_credentials(cli, &mut config)?;
+    apply_run_args(args, &mut config)?;
+    config.max_runtime = remaining;
 
-    info!("IMAP Filter execution completed successfully.");
-    Ok(())
+    let last_run = args
+        .since_last_run
+        .then(|| last_run_path(&cli.config, name));
+    let started = chrono::Utc::now();
+    if let Some(path) = &last_run {
+        match read_last_run(path)? {
+            Some(timestamp) => {
+                info!("Only fetching messages since the last run at {}", timestamp);
+                config.since = Some(since_date(timestamp));
+            }
+            None => info!(
+                "No previous run recorded in {}; scanning everything",
+                path.display()
+            ),
+        }
+    }
+
+    debug!("Loaded {} filters.", config.filters.len());
+    debug!("Filters: {:?}", config.filters);
+
+    let summary = imap_filter::run_until(config, creds, stop.clone())?;
+    if let Some(path) = &last_run {
+        if summary.interrupted {
+            info!(
+                "Not recording this run in {}; it was interrupted",
+                path.display()
+            );
+        } else if summary.errors == 0 {
+            write_last_run(path, started)?;
+        } else {
+            info!(
+                "Not recording this run in {}; {} action(s) failed",
+                path.display(),
+                summary.errors
+            );
+        }
+    }
+    info!(
+        "Fetched {} messages, {} matched a filter; {} action(s) applied, {} failed.",
+        summary.fetched, summary.matched, summary.applied, summary.errors
+    );
+    Ok(summary)
+}
+
+/// Sets `stop` on the first Ctrl-C so the run winds down cleanly; a second
+/// Ctrl-C exits immediately.
+fn install_interrupt_handler(stop: Arc<AtomicBool>) {
+    let result = ctrlc::set_handler(move || {
+        if stop.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted; finishing the current message (Ctrl-C again to abort)");
+    });
+    if let Err(e) = result {
+        error!("Failed to install the Ctrl-C handler: {}", e);
+    }
+}
+
+/// Prints what a run would use for every selected account, without
+/// resolving credentials or connecting.
+fn explain(cli: &Cli, args: &RunArgs) -> Result<i32> {
+    let accounts = load_configs(&cli.config)?.into_accounts(cli.account.as_deref())?;
+    for (name, mut config) in accounts {
+        apply_run_args(args, &mut config)?;
+        println!("account: {}", name);
+        config.filter_options().print_details();
+        for filter in &config.filters {
+            filter.print_details();
+        }
+        println!();
+    }
+    Ok(EXIT_NOTHING_TO_DO)
+}
+
+/// Runs every selected account and returns the most severe exit code among them.
+fn run(cli: &Cli, args: &RunArgs) -> Result<i32> {
+    if args.explain {
+        return explain(cli, args);
+    }
+    let accounts = load_configs(&cli.config)?.into_accounts(cli.account.as_deref())?;
+    let stop = Arc::new(AtomicBool::new(false));
+    install_interrupt_handler(stop.clone());
+    let deadline = args.max_runtime.map(|budget| Instant::now() + budget);
+
+    let mut failed = Vec::new();
+    let mut exit_code = 0;
+    let mut actions = Vec::new();
+    for (name, config) in accounts {
+        if stop.load(Ordering::SeqCst) {
+            info!("Interrupted; skipping account '{}'", name);
+            continue;
+        }
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            info!("Max runtime reached; skipping account '{}'", name);
+            exit_code = exit_code.max(EXIT_TIMED_OUT);
+            continue;
+        }
+        info!("Running account '{}'", name);
+        match run_account(cli, args, &name, config, &stop, remaining) {
+            Ok(summary) => {
+                exit_code = exit_code.max(summary.exit_code());
+                actions.extend(summary.actions);
+            }
+            Err(e) => {
+                error!("Account '{}' failed: {:?}", name, e);
+                failed.push(name);
+            }
+        }
+    }
+    if let Some(path) = &args.report {
+        let file = File::create(path)
+            .map_err(|e| eyre!("Failed to create report {}: {}", path.display(), e))?;
+        write_report(&actions, args.output_format, io::BufWriter::new(file))?;
+        info!(
+            "Wrote {} action record(s) to {}",
+            actions.len(),
+            path.display()
+        );
+    }
+    if !failed.is_empty() {
+        return Err(eyre!("Failed account(s): {:?}", failed));
+    }
+
+    Ok(exit_code)
+}
+
+/// Logs in to every selected account and prints its INBOX message count.
+fn healthcheck(cli: &Cli) -> Result<i32> {
+    let accounts = load_configs(&cli.config)?.into_accounts(cli.account.as_deref())?;
+
+    let mut failed = Vec::new();
+    for (name, mut config) in accounts {
+        let result = account_credentials(cli, &mut config)
+            .and_then(|creds| Ok(imap_filter::healthcheck(config, creds)?));
+        match result {
+            Ok(count) => println!("{}: OK, {} message(s) in INBOX", name, count),
+            Err(e) => {
+                error!("Healthcheck for account '{}' failed: {:?}", name, e);
+                println!("{}: FAILED, {}", name, e);
+                failed.push(name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(eyre!("Failed account(s): {:?}", failed));
+    }
+
+    Ok(EXIT_NOTHING_TO_DO)
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let command = cli.subcommand().unwrap_or_else(|e| e.exit());
+
+    setup_logging(cli.verbose);
+    info!("=====================================================================================================================");
+    info!("Starting IMAP Filter");
+    debug!("Parsed CLI arguments: {:?}", cli);
+
+    let result = match command {
+        Command::Run(args) => run(&cli, &args),
+        Command::Healthcheck => healthcheck(&cli),
+    };
+    match result {
+        Ok(exit_code) => {
+            info!("IMAP Filter execution completed (exit code {}).", exit_code);
+            std::process::exit(exit_code);
+        }
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(EXIT_FATAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_debug_redacts_password() {
+        let cli = Cli::parse_from([
+            "imap-filter",
+            "--imap-password",
+            "hunter2",
+            "--password-command",
+            "echo hunter3",
+        ]);
+
+        let output = format!("{:?}", cli);
+        assert!(!output.contains("hunter2"), "password leaked: {}", output);
+        assert!(
+            !output.contains("hunter3"),
+            "password command leaked: {}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_subcommand_dispatch() {
+        let command = |args: &[&str]| Cli::try_parse_from(args).unwrap().subcommand();
+
+        let expected = Command::Run(RunArgs {
+            limit: Some(5),
+            ..Default::default()
+        });
+        assert_eq!(command(&["imap-filter", "--limit", "5"]).unwrap(), expected);
+        assert_eq!(
+            command(&["imap-filter", "run", "--limit", "5"]).unwrap(),
+            expected
+        );
+        assert_eq!(
+            command(&["imap-filter", "-c", "a.yml", "healthcheck"]).unwrap(),
+            Command::Healthcheck
+        );
+        assert_eq!(
+            command(&["imap-filter", "healthcheck", "--account", "home"]).unwrap(),
+            Command::Healthcheck
+        );
+
+        assert!(command(&["imap-filter", "--limit", "5", "healthcheck"]).is_err());
+        assert!(Cli::try_parse_from(["imap-filter", "healthcheck", "--limit", "5"]).is_err());
+    }
+
+    #[test]
+    fn test_max_runtime_parsing() {
+        let cli = Cli::parse_from(["imap-filter", "--max-runtime", "10m"]);
+        assert_eq!(cli.run.max_runtime, Some(Duration::from_secs(600)));
+        assert!(Cli::try_parse_from(["imap-filter", "--max-runtime", "soon"]).is_err());
+        assert!(Cli::try_parse_from(["imap-filter", "--max-runtime", "999999999999999w"]).is_err());
+    }
+
+    #[test]
+    fn test_verbosity_level() {
+        assert_eq!(verbosity_level(0), None);
+        assert_eq!(verbosity_level(1), Some(LevelFilter::Info));
+        assert_eq!(verbosity_level(2), Some(LevelFilter::Debug));
+        assert_eq!(verbosity_level(5), Some(LevelFilter::Trace));
+
+        let cli = Cli::parse_from(["imap-filter", "-vv"]);
+        assert_eq!(cli.verbose, 2);
+    }
 }
@@ -1,23 +1,33 @@
+use chrono::{DateTime, FixedOffset, Utc};
+use mailparse::{
+    addrparse_header, dateparse, parse_headers, parse_mail, DispositionType, MailAddr, MailHeader,
+    MailHeaderMap, ParsedMail,
+};
+use serde::Deserialize;
 use std::collections::HashMap;
-use mailparse::{addrparse, MailAddr};
-use serde::{Deserialize, Serialize};
 
-use crate::message_filter::MessageFilter;
 use crate::address_filter::AddressFilter;
+use crate::message_filter::{MessageFilter, RecipientFields};
 
-fn parse_email_header(header: &str) -> Vec<(String, String)> {
-    match addrparse(header) {
+/// Parses an address header, decoding RFC 2047 encoded-word display names.
+fn parse_email_header(header: &MailHeader) -> Vec<(String, String)> {
+    match addrparse_header(header) {
         Ok(parsed) => parsed
             .iter()
             .flat_map(|addr| match addr {
-                MailAddr::Single(info) => vec![
-                    (info.display_name.clone().unwrap_or_default(), info.addr.clone())
-                ],
-                MailAddr::Group(group) => group.addrs.iter()
-                    .map(|info| (
-                        info.display_name.clone().unwrap_or_default(),
-                        info.addr.clone()
-                    ))
+                MailAddr::Single(info) => vec![(
+                    info.display_name.clone().unwrap_or_default(),
+                    info.addr.clone(),
+                )],
+                MailAddr::Group(group) => group
+                    .addrs
+                    .iter()
+                    .map(|info| {
+                        (
+                            info.display_name.clone().unwrap_or_default(),
+                            info.addr.clone(),
+                        )
+                    })
                     .collect(),
             })
             .collect(),
@@ -25,82 +35,940 @@ fn parse_email_header(header: &str) -> Vec<(String, String)> {
     }
 }
 
-#[derive(Debug)]
+/// Which timestamp a message's age is measured from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgeBasis {
+    /// The server's arrival time (INTERNALDATE).
+    #[default]
+    Internaldate,
+    /// The sender's `Date:` header, falling back to INTERNALDATE when absent.
+    DateHeader,
+}
+
+/// `dateparse` is lenient and returns the epoch for text with no date in it,
+/// so that result is treated as unparseable.
+fn parse_date_header(value: &str) -> Option<DateTime<Utc>> {
+    dateparse(value)
+        .ok()
+        .filter(|&timestamp| timestamp != 0)
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+}
+
+/// Extracts the list identifier from a `List-Id` value such as
+/// `Developers <dev.example.com>`, falling back to the whole value.
+fn parse_list_id(value: &str) -> String {
+    match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => value[start + 1..end].trim().to_string(),
+        _ => value.trim().to_string(),
+    }
+}
+
+/// Subject prefixes that mark a reply or forward, lowercased.
+const REPLY_PREFIXES: [&str; 4] = ["re:", "fwd:", "fw:", "aw:"];
+
+/// A reply or forward: it threads onto an earlier message via
+/// `In-Reply-To`/`References`, or its subject carries a `Re:`/`Fwd:` prefix.
+fn is_reply(headers: &HashMap<String, MailHeader<'_>>, subject: &str) -> bool {
+    let subject = subject.trim_start().to_lowercase();
+    headers.contains_key("in-reply-to")
+        || headers.contains_key("references")
+        || REPLY_PREFIXES
+            .iter()
+            .any(|prefix| subject.starts_with(prefix))
+}
+
+/// Parses the header block into a map keyed by lowercased header name, so
+/// lookups are case-insensitive. Only the first occurrence of a repeated
+/// header is kept.
+fn header_map(raw_data: &[u8]) -> HashMap<String, MailHeader<'_>> {
+    let Ok((headers, _)) = parse_headers(raw_data) else {
+        return HashMap::new();
+    };
+    let mut map = HashMap::new();
+    for header in headers {
+        map.entry(header.get_key().to_ascii_lowercase())
+            .or_insert(header);
+    }
+    map
+}
+
+/// The addresses in every occurrence of each of `names`, which `header_map`
+/// would cut down to the first. Delivery headers repeat when mail passes
+/// through aliases, forwards or groups.
+fn all_addresses(raw_data: &[u8], names: &[&str]) -> Vec<(String, String)> {
+    let Ok((headers, _)) = parse_headers(raw_data) else {
+        return Vec::new();
+    };
+    names
+        .iter()
+        .flat_map(|name| headers.get_all_headers(name))
+        .flat_map(parse_email_header)
+        .collect()
+}
+
+/// True when some MIME part is an attachment: explicitly
+/// `Content-Disposition: attachment`, or a named part with no disposition.
+/// Inline parts (e.g. embedded images) don't count.
+fn part_has_attachment(part: &ParsedMail) -> bool {
+    let is_attachment = match part.headers.get_first_value("Content-Disposition") {
+        Some(_) => part.get_content_disposition().disposition == DispositionType::Attachment,
+        None => part.ctype.params.contains_key("name"),
+    };
+    is_attachment || part.subparts.iter().any(part_has_attachment)
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Message {
     pub uid: u32,
+    /// Sequence number in the mailbox when fetched; 0 when unknown.
+    pub seq: u32,
     pub to: Vec<(String, String)>,
     pub cc: Vec<(String, String)>,
     pub from: Vec<(String, String)>,
+    pub reply_to: Vec<(String, String)>,
+    pub sender: Vec<(String, String)>,
+    /// The delivery targets from every `Delivered-To` and `X-Original-To`,
+    /// which name you even when you were only Bcc'd.
+    pub delivered_to: Vec<(String, String)>,
     pub subject: String,
+    pub internal_date: Option<DateTime<FixedOffset>>,
+    pub date: Option<DateTime<Utc>>,
+    /// Size in bytes (RFC822.SIZE), filled in from the fetch.
+    pub size: Option<u64>,
+    pub has_attachment: bool,
+    /// The message replies to or forwards another, by headers or subject prefix.
+    pub is_reply: bool,
+    /// The message has the `\Seen` flag, filled in from the fetch.
+    pub seen: bool,
+    pub message_id: Option<String>,
+    pub list_id: Option<String>,
+    pub list_unsubscribe: Option<String>,
+    /// Every header's decoded value, keyed by lowercased name; the first
+    /// occurrence wins.
+    pub headers: HashMap<String, String>,
+    /// Gmail's X-GM-THRID, fetched only when a filter acts on whole threads.
+    pub thread_id: Option<u64>,
 }
 
 impl Message {
     pub fn new(raw_uid: u32, raw_data: Vec<u8>) -> Self {
-        let raw_string = String::from_utf8_lossy(&raw_data);
-        let headers: HashMap<String, String> = raw_string
-            .lines()
-            .filter_map(|line| line.split_once(": "))
-            .map(|(key, value)| (key.to_string(), value.to_string()))
-            .collect();
+        let headers = header_map(&raw_data);
+        // Unfolded, with RFC 2047 encoded-words decoded
+        let value = |name: &str| headers.get(name).map(|header| header.get_value());
 
-        let to_list = headers.get("To").map(|s| parse_email_header(s)).unwrap_or_default();
-        let cc_list = headers.get("Cc").map(|s| parse_email_header(s)).unwrap_or_default();
-        let from_list = headers.get("From").map(|s| parse_email_header(s)).unwrap_or_default();
+        let to_list = headers
+            .get("to")
+            .map(parse_email_header)
+            .unwrap_or_default();
+        let cc_list = headers
+            .get("cc")
+            .map(parse_email_header)
+            .unwrap_or_default();
+        let from_list = headers
+            .get("from")
+            .map(parse_email_header)
+            .unwrap_or_default();
+        let reply_to_list = headers
+            .get("reply-to")
+            .map(parse_email_header)
+            .unwrap_or_default();
+        let sender_list = headers
+            .get("sender")
+            .map(parse_email_header)
+            .unwrap_or_default();
+        let delivered_to_list = all_addresses(&raw_data, &["Delivered-To", "X-Original-To"]);
 
         Self {
             uid: raw_uid,
+            seq: 0,
             to: to_list,
             cc: cc_list,
             from: from_list,
-            subject: headers.get("Subject").cloned().unwrap_or_default(),
+            reply_to: reply_to_list,
+            sender: sender_list,
+            delivered_to: delivered_to_list,
+            subject: value("subject").unwrap_or_default(),
+            internal_date: None,
+            size: None,
+            seen: false,
+            has_attachment: parse_mail(&raw_data).is_ok_and(|mail| part_has_attachment(&mail)),
+            is_reply: is_reply(&headers, &value("subject").unwrap_or_default()),
+            date: value("date").and_then(|s| parse_date_header(&s)),
+            message_id: value("message-id").map(|s| s.trim().to_string()),
+            list_id: value("list-id").map(|s| parse_list_id(&s)),
+            list_unsubscribe: value("list-unsubscribe"),
+            headers: headers
+                .iter()
+                .map(|(name, header)| (name.clone(), header.get_value().trim().to_string()))
+                .collect(),
+            thread_id: None,
+        }
+    }
+
+    /// The Date header for log lines, or `unknown` when missing or unparseable.
+    pub fn sent_date(&self) -> String {
+        self.date.map_or_else(
+            || "unknown".to_string(),
+            |date| date.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        )
+    }
+
+    /// The timestamp age is measured from under `basis`.
+    pub fn timestamp(&self, basis: AgeBasis) -> Option<DateTime<Utc>> {
+        let internal_date = self.internal_date.map(|date| date.with_timezone(&Utc));
+        match basis {
+            AgeBasis::Internaldate => internal_date,
+            AgeBasis::DateHeader => self.date.or(internal_date),
         }
     }
 
-    fn matches_field(field: &Option<AddressFilter>, message: &Message, extractor: fn(&Message) -> &Vec<(String, String)>) -> bool {
+    /// The first From address, lowercased, used to group messages by sender.
+    pub fn sender_key(&self) -> String {
+        self.from
+            .first()
+            .map(|(_, email)| email.to_lowercase())
+            .unwrap_or_default()
+    }
+
+    fn matches_field(
+        field: &Option<AddressFilter>,
+        message_filter: &MessageFilter,
+        addresses: &[(String, String)],
+    ) -> bool {
         match field {
-            Some(filter) if filter.patterns.is_empty() => extractor(message).is_empty(),
-            Some(filter) => filter.matches(&extractor(message).iter().map(|(_, email)| email.clone()).collect::<Vec<_>>()),
+            Some(filter) if filter.patterns.is_empty() => addresses.is_empty(),
+            Some(filter) => filter.matches(
+                &addresses
+                    .iter()
+                    .map(|(_, email)| message_filter.normalize_address(email))
+                    .collect::<Vec<_>>(),
+            ),
+            None => true,
+        }
+    }
+
+    /// The addresses the filter's `to` matcher is applied to.
+    fn recipients(&self, fields: RecipientFields) -> Vec<(String, String)> {
+        match fields {
+            RecipientFields::To => self.to.clone(),
+            RecipientFields::Cc => self.cc.clone(),
+            RecipientFields::Both => self.to.iter().chain(&self.cc).cloned().collect(),
+        }
+    }
+
+    /// Like `matches_field` for a single-valued header: empty patterns
+    /// require the header to be absent.
+    fn matches_value(field: &Option<AddressFilter>, value: Option<&str>) -> bool {
+        match field {
+            Some(filter) if filter.patterns.is_empty() => value.is_none(),
+            Some(filter) => value.is_some_and(|value| filter.matches(&[value.to_string()])),
             None => true,
         }
     }
 
     pub fn compare(&self, filter: &MessageFilter) -> (bool, bool, bool) {
-        let from_match = Self::matches_field(&filter.from, self, |m| &m.from);
-        let to_match = Self::matches_field(&filter.to, self, |m| &m.to);
-        let cc_match = Self::matches_field(&filter.cc, self, |m| &m.cc);
+        let from_match = Self::matches_field(&filter.from, filter, &self.from);
+        let to_match = Self::matches_field(
+            &filter.to,
+            filter,
+            &self.recipients(filter.recipient_fields),
+        ) && (!filter.sole_recipient || (self.to.len() == 1 && self.cc.is_empty()));
+        let cc_match = Self::matches_field(&filter.cc, filter, &self.cc);
 
         (from_match, to_match, cc_match)
     }
+
+    fn matches_recipient_count(&self, filter: &MessageFilter) -> bool {
+        let count = self.to.len() + self.cc.len();
+        filter.min_recipients.is_none_or(|min| count >= min)
+            && filter.max_recipients.is_none_or(|max| count <= max)
+    }
+
+    /// An unknown size never satisfies a size bound.
+    fn matches_size(&self, filter: &MessageFilter) -> bool {
+        if filter.min_size.is_none() && filter.max_size.is_none() {
+            return true;
+        }
+        self.size.is_some_and(|size| {
+            filter.min_size.is_none_or(|min| size >= min)
+                && filter.max_size.is_none_or(|max| size <= max)
+        })
+    }
+
+    /// Checks the filter's `older_than`/`newer_than` bounds against the
+    /// message's age at `now`. An unknown date never satisfies an age bound.
+    pub fn matches_age(&self, filter: &MessageFilter, basis: AgeBasis, now: DateTime<Utc>) -> bool {
+        if filter.older_than.is_none() && filter.newer_than.is_none() {
+            return true;
+        }
+        self.timestamp(basis).is_some_and(|timestamp| {
+            let age = now - timestamp;
+            filter.older_than.is_none_or(|older_than| age > older_than)
+                && filter.newer_than.is_none_or(|newer_than| age < newer_than)
+        })
+    }
+
+    /// Every `headers` condition needs the header present with a matching value.
+    fn matches_headers(&self, filter: &MessageFilter) -> bool {
+        filter.headers.iter().all(|(name, pattern)| {
+            self.headers
+                .get(name)
+                .is_some_and(|value| pattern.matches(std::slice::from_ref(value)))
+        })
+    }
+
+    /// True when every address field and condition of the filter matches.
+    pub fn matches(&self, filter: &MessageFilter) -> bool {
+        let (from_match, to_match, cc_match) = self.compare(filter);
+        from_match
+            && to_match
+            && cc_match
+            && Self::matches_field(&filter.reply_to, filter, &self.reply_to)
+            && Self::matches_field(&filter.sender, filter, &self.sender)
+            && Self::matches_field(&filter.delivered_to, filter, &self.delivered_to)
+            && filter.subject.matches(&self.subject)
+            && filter
+                .subject_empty
+                .is_none_or(|wanted| wanted == self.subject.trim().is_empty())
+            && Self::matches_value(&filter.list_id, self.list_id.as_deref())
+            && self.matches_headers(filter)
+            && self.matches_recipient_count(filter)
+            && self.matches_size(filter)
+            && filter
+                .has_attachment
+                .is_none_or(|wanted| wanted == self.has_attachment)
+            && filter.is_reply.is_none_or(|wanted| wanted == self.is_reply)
+            && filter.seen.is_none_or(|wanted| wanted == self.seen)
+    }
 }
 
 #[test]
 fn test_only_me_star_filter_behavior() {
     let filter = MessageFilter {
         name: "only-me-star".to_string(),
-        to: Some(AddressFilter { patterns: vec!["scott.idler@tatari.tv".to_string()] }),
-        from: Some(AddressFilter { patterns: vec!["*@tatari.tv".to_string()] }),
-        cc: Some(AddressFilter { patterns: vec![] }), // Must match emails with no CCs
+        to: Some(AddressFilter::new(vec!["scott.idler@tatari.tv".to_string()]).unwrap()),
+        from: Some(AddressFilter::new(vec!["*@tatari.tv".to_string()]).unwrap()),
+        cc: Some(AddressFilter::new(vec![]).unwrap()), // Must match emails with no CCs
         move_to: None,
         star: Some(true),
+        ..Default::default()
     };
 
     let matching_email = Message {
         uid: 1,
-        to: vec![("Scott Idler".to_string(), "scott.idler@tatari.tv".to_string())],
-        from: vec![("Scott Idler".to_string(), "scott.idler@tatari.tv".to_string())],
+        to: vec![(
+            "Scott Idler".to_string(),
+            "scott.idler@tatari.tv".to_string(),
+        )],
+        from: vec![(
+            "Scott Idler".to_string(),
+            "scott.idler@tatari.tv".to_string(),
+        )],
         cc: vec![], // This should match since the filter has an explicit empty CC
         subject: "only to me".to_string(),
+        ..Default::default()
     };
 
     let non_matching_email = Message {
         uid: 2,
-        to: vec![("Scott Idler".to_string(), "scott.idler@tatari.tv".to_string())],
-        from: vec![("Scott Idler".to_string(), "scott.idler@tatari.tv".to_string())],
+        to: vec![(
+            "Scott Idler".to_string(),
+            "scott.idler@tatari.tv".to_string(),
+        )],
+        from: vec![(
+            "Scott Idler".to_string(),
+            "scott.idler@tatari.tv".to_string(),
+        )],
         cc: vec![("Someone Else".to_string(), "someone@tatari.tv".to_string())], // Should NOT match
         subject: "cc included".to_string(),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        matching_email.compare(&filter),
+        (true, true, true),
+        "Matching email should be accepted"
+    );
+    assert_eq!(
+        non_matching_email.compare(&filter),
+        (true, true, false),
+        "Non-matching email should be rejected due to CC"
+    );
+}
+
+#[test]
+fn test_sole_recipient_rejects_one_of_many() {
+    let filter = MessageFilter {
+        to: Some(AddressFilter::new(vec!["me@example.com".to_string()]).unwrap()),
+        sole_recipient: true,
+        ..Default::default()
+    };
+    let address = |email: &str| ("".to_string(), email.to_string());
+
+    let direct = Message {
+        to: vec![address("me@example.com")],
+        ..Default::default()
+    };
+    let one_of_many = Message {
+        to: vec![address("me@example.com"), address("team@example.com")],
+        ..Default::default()
+    };
+    let copied = Message {
+        to: vec![address("me@example.com")],
+        cc: vec![address("boss@example.com")],
+        ..Default::default()
+    };
+    let someone_else = Message {
+        to: vec![address("you@example.com")],
+        ..Default::default()
+    };
+
+    assert_eq!(direct.compare(&filter), (true, true, true));
+    assert_eq!(one_of_many.compare(&filter), (true, false, true));
+    assert!(!copied.matches(&filter));
+    assert!(!someone_else.matches(&filter));
+    assert!(one_of_many.matches(&MessageFilter {
+        sole_recipient: false,
+        ..filter
+    }));
+}
+
+#[test]
+fn test_min_recipients_filter_behavior() {
+    let filter = MessageFilter {
+        name: "blast".to_string(),
+        min_recipients: Some(5),
+        ..Default::default()
+    };
+
+    let recipient = |i: usize| ("".to_string(), format!("user{}@example.com", i));
+
+    let single = Message {
+        uid: 1,
+        to: vec![recipient(0)],
+        ..Default::default()
+    };
+
+    let blast = Message {
+        uid: 2,
+        to: (0..6).map(recipient).collect(),
+        cc: (6..10).map(recipient).collect(),
+        ..Default::default()
+    };
+
+    assert!(
+        !single.matches(&filter),
+        "Single-recipient email should not match min_recipients: 5"
+    );
+    assert!(
+        blast.matches(&filter),
+        "Ten-recipient email should match min_recipients: 5"
+    );
+}
+
+#[test]
+fn test_max_recipients_filter_behavior() {
+    let filter = MessageFilter {
+        name: "small".to_string(),
+        max_recipients: Some(2),
+        ..Default::default()
+    };
+
+    let recipient = |i: usize| ("".to_string(), format!("user{}@example.com", i));
+
+    let pair = Message {
+        uid: 1,
+        to: vec![recipient(0)],
+        cc: vec![recipient(1)],
+        ..Default::default()
+    };
+    let crowd = Message {
+        uid: 2,
+        to: (0..3).map(recipient).collect(),
+        ..Default::default()
+    };
+
+    assert!(pair.matches(&filter));
+    assert!(!crowd.matches(&filter));
+}
+
+#[test]
+fn test_date_header_parsing() {
+    let raw =
+        b"From: a@example.com\r\nDate: Tue, 1 Jul 2003 10:52:37 +0200\r\nSubject: old\r\n\r\nbody"
+            .to_vec();
+    let message = Message::new(1, raw);
+
+    assert_eq!(
+        message.date,
+        Some(
+            DateTime::parse_from_rfc3339("2003-07-01T08:52:37Z")
+                .unwrap()
+                .with_timezone(&Utc)
+        )
+    );
+    assert_eq!(message.sent_date(), "2003-07-01 08:52:37 UTC");
+}
+
+#[test]
+fn test_invalid_date_header_is_none() {
+    let raw =
+        b"From: a@example.com\r\nDate: sometime last week\r\nSubject: odd\r\n\r\nbody".to_vec();
+    let message = Message::new(1, raw);
+
+    assert_eq!(message.date, None);
+    assert_eq!(message.sent_date(), "unknown");
+    assert_eq!(
+        Message::new(2, b"Subject: undated\r\n\r\nbody".to_vec()).date,
+        None
+    );
+}
+
+#[test]
+fn test_timestamp_basis_and_fallback() {
+    let internal = DateTime::parse_from_rfc3339("2025-01-31T12:00:00+00:00").unwrap();
+    let header = DateTime::parse_from_rfc3339("2020-06-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+
+    let imported = Message {
+        internal_date: Some(internal),
+        date: Some(header),
+        ..Default::default()
+    };
+    assert_eq!(
+        imported.timestamp(AgeBasis::Internaldate),
+        Some(internal.with_timezone(&Utc))
+    );
+    assert_eq!(imported.timestamp(AgeBasis::DateHeader), Some(header));
+
+    let undated = Message {
+        internal_date: Some(internal),
+        date: None,
+        ..Default::default()
+    };
+    assert_eq!(
+        undated.timestamp(AgeBasis::DateHeader),
+        Some(internal.with_timezone(&Utc))
+    );
+}
+
+#[test]
+fn test_list_id_extraction_and_matching() {
+    let raw = b"From: bot@example.com\r\nList-Id: Developers <dev.example.com>\r\nList-Unsubscribe: <mailto:leave@example.com>\r\nSubject: digest\r\n\r\nbody".to_vec();
+    let message = Message::new(1, raw);
+
+    assert_eq!(message.list_id.as_deref(), Some("dev.example.com"));
+    assert_eq!(
+        message.list_unsubscribe.as_deref(),
+        Some("<mailto:leave@example.com>")
+    );
+
+    let list_filter = |pattern: &str| MessageFilter {
+        list_id: Some(AddressFilter::new(vec![pattern.to_string()]).unwrap()),
+        ..Default::default()
+    };
+    assert!(message.matches(&list_filter("dev.example.com")));
+    assert!(message.matches(&list_filter("*.example.com")));
+    assert!(!message.matches(&list_filter("ops.example.com")));
+
+    let direct = Message::new(
+        2,
+        b"From: a@example.com\r\nSubject: hi\r\n\r\nbody".to_vec(),
+    );
+    assert!(!direct.matches(&list_filter("*")));
+    assert!(direct.matches(&MessageFilter {
+        list_id: Some(AddressFilter::new(vec![]).unwrap()),
+        ..Default::default()
+    }));
+}
+
+#[test]
+fn test_custom_header_matching() {
+    let spam = Message::new(1, b"From: a@example.com\r\nX-Spam-Flag: YES\r\nx-mailer: Acme Mailer 2.1\r\nSubject: win\r\n\r\nbody".to_vec());
+    let ham = Message::new(
+        2,
+        b"From: a@example.com\r\nX-Spam-Flag: NO\r\nSubject: hi\r\n\r\nbody".to_vec(),
+    );
+    let unflagged = Message::new(
+        3,
+        b"From: a@example.com\r\nSubject: hi\r\n\r\nbody".to_vec(),
+    );
+
+    let filter: MessageFilter = serde_yaml::from_str("headers:\n  X-Spam-Flag: 'YES'\n").unwrap();
+    assert!(spam.matches(&filter));
+    assert!(!ham.matches(&filter));
+    assert!(!unflagged.matches(&filter));
+
+    let filter: MessageFilter =
+        serde_yaml::from_str("headers:\n  X-Spam-Flag: 'YES'\n  X-Mailer: 'Acme Mailer *'\n")
+            .unwrap();
+    assert!(spam.matches(&filter));
+}
+
+#[test]
+fn test_reply_to_matches_when_from_does_not() {
+    let raw = b"From: noreply@tickets.example.com\r\nReply-To: Jane Doe <jane@customer.com>\r\nSender: relay@tickets.example.com\r\nSubject: re: help\r\n\r\nbody".to_vec();
+    let message = Message::new(1, raw);
+
+    assert_eq!(
+        message.reply_to,
+        vec![("Jane Doe".to_string(), "jane@customer.com".to_string())]
+    );
+    assert_eq!(
+        message.sender,
+        vec![("".to_string(), "relay@tickets.example.com".to_string())]
+    );
+
+    let from_filter = MessageFilter {
+        from: Some(AddressFilter::new(vec!["*@customer.com".to_string()]).unwrap()),
+        ..Default::default()
+    };
+    let reply_to_filter = MessageFilter {
+        reply_to: Some(AddressFilter::new(vec!["*@customer.com".to_string()]).unwrap()),
+        ..Default::default()
+    };
+    let sender_filter = MessageFilter {
+        sender: Some(AddressFilter::new(vec!["relay@*".to_string()]).unwrap()),
+        ..Default::default()
+    };
+
+    assert!(!message.matches(&from_filter));
+    assert!(message.matches(&reply_to_filter));
+    assert!(message.matches(&sender_filter));
+}
+
+#[test]
+fn test_bcc_matches_via_delivered_to() {
+    let raw = b"From: boss@tatari.tv\r\nTo: team@tatari.tv\r\nDelivered-To: scott@tatari.tv\r\nSubject: fyi\r\n\r\nbody".to_vec();
+    let message = Message::new(1, raw);
+    assert_eq!(
+        message.delivered_to,
+        vec![("".to_string(), "scott@tatari.tv".to_string())]
+    );
+
+    let to_me = MessageFilter {
+        to: Some(AddressFilter::new(vec!["scott@tatari.tv".to_string()]).unwrap()),
+        ..Default::default()
+    };
+    let delivered_to_me = MessageFilter {
+        delivered_to: Some(AddressFilter::new(vec!["scott@tatari.tv".to_string()]).unwrap()),
+        ..Default::default()
+    };
+    assert!(!message.matches(&to_me), "a Bcc'd address is not in To");
+    assert!(message.matches(&delivered_to_me));
+
+    let original = Message::new(
+        2,
+        b"To: team@tatari.tv\r\nX-Original-To: scott@tatari.tv\r\n\r\nbody".to_vec(),
+    );
+    assert!(original.matches(&delivered_to_me));
+    assert!(
+        !Message::new(3, b"To: scott@tatari.tv\r\n\r\nbody".to_vec()).matches(&delivered_to_me)
+    );
+
+    let forwarded = Message::new(
+        4,
+        b"To: team@tatari.tv\r\nDelivered-To: team@tatari.tv\r\nDelivered-To: scott@tatari.tv\r\n\r\nbody"
+            .to_vec(),
+    );
+    assert_eq!(forwarded.delivered_to.len(), 2);
+    assert!(
+        forwarded.matches(&delivered_to_me),
+        "the second Delivered-To counts too"
+    );
+}
+
+#[test]
+fn test_plus_addressing_normalization() {
+    let message = Message {
+        to: vec![("".to_string(), "scott+newsletters@tatari.tv".to_string())],
+        ..Default::default()
+    };
+    let filter = |plus: bool| MessageFilter {
+        to: Some(AddressFilter::new(vec!["scott@tatari.tv".to_string()]).unwrap()),
+        normalize_plus_addressing: plus,
+        ..Default::default()
+    };
+
+    assert!(!message.matches(&filter(false)));
+    assert!(message.matches(&filter(true)));
+}
+
+#[test]
+fn test_dot_normalization() {
+    let message = Message {
+        from: vec![("".to_string(), "s.c.o.t.t+promo@gmail.com".to_string())],
+        ..Default::default()
+    };
+    let filter = |dots: bool| MessageFilter {
+        from: Some(AddressFilter::new(vec!["scott@gmail.com".to_string()]).unwrap()),
+        normalize_plus_addressing: true,
+        normalize_dots: dots,
+        ..Default::default()
+    };
+
+    assert!(!message.matches(&filter(false)));
+    assert!(message.matches(&filter(true)));
+}
+
+#[test]
+fn test_min_size_filter() {
+    let filter = MessageFilter {
+        min_size: Some(1024 * 1024),
+        ..Default::default()
+    };
+    let sized = |size| Message {
+        size,
+        ..Default::default()
+    };
+
+    assert!(sized(Some(5 * 1024 * 1024)).matches(&filter));
+    assert!(sized(Some(1024 * 1024)).matches(&filter));
+    assert!(!sized(Some(500 * 1024)).matches(&filter));
+    assert!(!sized(None).matches(&filter));
+    assert!(sized(None).matches(&MessageFilter::default()));
+}
+
+#[test]
+fn test_has_attachment() {
+    let multipart = |second_part: &str| {
+        format!(
+            "From: a@example.com\r\nSubject: report\r\nMIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=\"XX\"\r\n\r\n\
+             --XX\r\nContent-Type: text/plain\r\n\r\nsee attached\r\n\
+             --XX\r\n{}\r\n\r\nAAAA\r\n--XX--\r\n",
+            second_part
+        )
+    };
+    let with_attachment = Message::new(1, multipart("Content-Type: application/pdf; name=\"r.pdf\"\r\nContent-Disposition: attachment; filename=\"r.pdf\"").into_bytes());
+    let with_inline_image = Message::new(2, multipart("Content-Type: image/png; name=\"logo.png\"\r\nContent-Disposition: inline; filename=\"logo.png\"").into_bytes());
+    let plain = Message::new(
+        3,
+        b"From: a@example.com\r\nSubject: hi\r\n\r\nbody".to_vec(),
+    );
+
+    assert!(with_attachment.has_attachment);
+    assert!(!with_inline_image.has_attachment);
+    assert!(!plain.has_attachment);
+
+    let filter = MessageFilter {
+        has_attachment: Some(true),
+        ..Default::default()
     };
+    assert!(with_attachment.matches(&filter));
+    assert!(!plain.matches(&filter));
+    assert!(plain.matches(&MessageFilter {
+        has_attachment: Some(false),
+        ..Default::default()
+    }));
+}
+
+#[test]
+fn test_subject_empty() {
+    let untitled = Message::new(1, b"From: a@example.com\r\nSubject:  \r\n\r\nbody".to_vec());
+    let missing = Message::new(2, b"From: a@example.com\r\n\r\nbody".to_vec());
+    let titled = Message::new(
+        3,
+        b"From: a@example.com\r\nSubject: hi\r\n\r\nbody".to_vec(),
+    );
+
+    let empty = MessageFilter {
+        subject_empty: Some(true),
+        ..Default::default()
+    };
+    assert!(untitled.matches(&empty));
+    assert!(missing.matches(&empty));
+    assert!(!titled.matches(&empty));
+    assert!(titled.matches(&MessageFilter {
+        subject_empty: Some(false),
+        ..Default::default()
+    }));
+}
+
+#[test]
+fn test_empty_from_list_matches_only_from_less_messages() {
+    let filter: MessageFilter = serde_yaml::from_str("from: []").unwrap();
+    let anonymous = Message::new(
+        1,
+        b"To: me@example.com\r\nSubject: who?\r\n\r\nbody".to_vec(),
+    );
+    let signed = Message::new(
+        2,
+        b"From: a@example.com\r\nTo: me@example.com\r\n\r\nbody".to_vec(),
+    );
+
+    assert!(anonymous.matches(&filter));
+    assert!(!signed.matches(&filter));
+}
+
+#[test]
+fn test_is_reply() {
+    let reply = Message::new(
+        1,
+        b"From: a@example.com\r\nIn-Reply-To: <1@example.com>\r\nSubject: lunch?\r\n\r\nsure"
+            .to_vec(),
+    );
+    let forward = Message::new(
+        2,
+        b"From: a@example.com\r\nSubject: FWD: lunch?\r\n\r\nfyi".to_vec(),
+    );
+    let fresh = Message::new(
+        3,
+        b"From: a@example.com\r\nSubject: Regarding lunch\r\n\r\nhungry?".to_vec(),
+    );
+
+    assert!(reply.is_reply);
+    assert!(forward.is_reply);
+    assert!(!fresh.is_reply, "'Regarding' is not a Re: prefix");
+
+    let replies = MessageFilter {
+        is_reply: Some(true),
+        ..Default::default()
+    };
+    let originals = MessageFilter {
+        is_reply: Some(false),
+        ..Default::default()
+    };
+    assert!(reply.matches(&replies));
+    assert!(!reply.matches(&originals));
+    assert!(fresh.matches(&originals));
+}
+
+#[test]
+fn test_header_names_are_case_insensitive() {
+    let raw = b"from: Boss <boss@tatari.tv>\r\nTO: me@example.com\r\nsubject: hi\r\n\r\nFrom: body@example.com\r\n".to_vec();
+    let message = Message::new(1, raw);
+
+    assert_eq!(
+        message.from,
+        vec![("Boss".to_string(), "boss@tatari.tv".to_string())]
+    );
+    assert_eq!(
+        message.to,
+        vec![("".to_string(), "me@example.com".to_string())]
+    );
+    assert_eq!(message.subject, "hi");
+}
+
+#[test]
+fn test_subject_with_colons_and_folding() {
+    let raw = b"From: a@example.com\r\nSubject: Re: meeting: notes\r\nCc: one@example.com,\r\n two@example.com\r\n\r\nbody".to_vec();
+    let message = Message::new(1, raw);
+
+    assert_eq!(message.subject, "Re: meeting: notes");
+    assert_eq!(message.cc.len(), 2);
+}
+
+#[test]
+fn test_encoded_word_subjects_are_decoded() {
+    let q_encoded = Message::new(
+        1,
+        b"From: a@example.com\r\nSubject: =?UTF-8?Q?Caf=C3=A9_menu?=\r\n\r\nbody".to_vec(),
+    );
+    assert_eq!(q_encoded.subject, "Caf\u{e9} menu");
+
+    let b_encoded = Message::new(
+        2,
+        b"From: a@example.com\r\nSubject: =?UTF-8?B?Q2Fmw6k=?= =?UTF-8?B?IG1lbnU=?=\r\n\r\nbody"
+            .to_vec(),
+    );
+    assert_eq!(b_encoded.subject, "Caf\u{e9} menu");
+}
+
+#[test]
+fn test_encoded_word_display_names_are_decoded() {
+    let raw = b"From: =?ISO-8859-1?Q?Ren=E9?= Dupont <rene@example.fr>\r\nSubject: hi\r\n\r\nbody"
+        .to_vec();
+    let message = Message::new(1, raw);
+    assert_eq!(
+        message.from,
+        vec![(
+            "Ren\u{e9} Dupont".to_string(),
+            "rene@example.fr".to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_older_than_and_newer_than() {
+    let now = DateTime::parse_from_rfc3339("2025-01-31T12:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let aged = |days| Message {
+        internal_date: Some((now - chrono::TimeDelta::days(days)).fixed_offset()),
+        ..Default::default()
+    };
+    let older = MessageFilter {
+        older_than: Some(chrono::TimeDelta::days(3)),
+        ..Default::default()
+    };
+    let newer = MessageFilter {
+        newer_than: Some(chrono::TimeDelta::days(3)),
+        ..Default::default()
+    };
+
+    assert!(aged(5).matches_age(&older, AgeBasis::Internaldate, now));
+    assert!(!aged(1).matches_age(&older, AgeBasis::Internaldate, now));
+    assert!(aged(1).matches_age(&newer, AgeBasis::Internaldate, now));
+    assert!(!aged(5).matches_age(&newer, AgeBasis::Internaldate, now));
+
+    assert!(
+        !Message::default().matches_age(&older, AgeBasis::Internaldate, now),
+        "unknown date never matches"
+    );
+    assert!(Message::default().matches_age(&MessageFilter::default(), AgeBasis::Internaldate, now));
+}
 
-    assert_eq!(matching_email.compare(&filter), (true, true, true), "Matching email should be accepted");
-    assert_eq!(non_matching_email.compare(&filter), (true, true, false), "Non-matching email should be rejected due to CC");
+#[test]
+fn test_recipient_fields_both_matches_cc_only_recipient() {
+    let cc_only = Message {
+        to: vec![("Team".to_string(), "team@tatari.tv".to_string())],
+        cc: vec![(
+            "Scott Idler".to_string(),
+            "scott.idler@tatari.tv".to_string(),
+        )],
+        ..Default::default()
+    };
+    let to_me = |recipient_fields| MessageFilter {
+        to: Some(AddressFilter::new(vec!["scott.idler@tatari.tv".to_string()]).unwrap()),
+        recipient_fields,
+        ..Default::default()
+    };
+
+    assert!(!cc_only.matches(&to_me(RecipientFields::To)));
+    assert!(cc_only.matches(&to_me(RecipientFields::Cc)));
+    assert!(cc_only.matches(&to_me(RecipientFields::Both)));
+
+    let to_only = Message {
+        cc: vec![],
+        ..cc_only.clone()
+    };
+    assert!(!to_only.matches(&to_me(RecipientFields::Both)));
 }
 
+#[test]
+fn test_seen_and_older_than() {
+    let now = Utc::now();
+    let message = |seen, days| Message {
+        seen,
+        internal_date: Some((now - chrono::TimeDelta::days(days)).fixed_offset()),
+        ..Default::default()
+    };
+    let read_and_old = MessageFilter {
+        seen: Some(true),
+        older_than: Some(chrono::TimeDelta::days(2)),
+        ..Default::default()
+    };
+    let is_match = |msg: &Message| {
+        msg.matches(&read_and_old) && msg.matches_age(&read_and_old, AgeBasis::Internaldate, now)
+    };
+
+    assert!(is_match(&message(true, 3)));
+    assert!(!is_match(&message(false, 3)), "unread messages don't match");
+    assert!(!is_match(&message(true, 1)), "recent messages don't match");
+    assert!(message(false, 0).matches(&MessageFilter {
+        seen: Some(false),
+        ..Default::default()
+    }));
+}
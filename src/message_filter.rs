@@ -1,45 +1,488 @@
-use serde::{Deserialize};
-use serde::de::{SeqAccess, Visitor, Deserializer};
+use crate::error::{FilterError, Result};
+use chrono::TimeDelta;
+use serde::de::{Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt;
 
 use crate::address_filter::AddressFilter;
+use crate::subject_filter::SubjectFilter;
 
-#[derive(Debug, Deserialize)]
+/// The mailbox filters read from unless they name another.
+pub const DEFAULT_MAILBOX: &str = "INBOX";
+
+/// Name given to the config's `default_filter_action` catch-all filter.
+pub const DEFAULT_FILTER_NAME: &str = "default_filter_action";
+
+/// Which recipient headers a filter's `to` patterns are matched against.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientFields {
+    #[default]
+    To,
+    Cc,
+    /// To and Cc combined, for lists that put you in Cc.
+    Both,
+}
+
+/// Where a filter moves matched messages: `move_to: Archive`, or the map
+/// form `move_to: { folder: Archive, keep_in_inbox: true }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "RawMoveTarget")]
+pub struct MoveTarget {
+    pub folder: String,
+    /// File the message under `folder` (label it on Gmail, copy it
+    /// elsewhere) without removing it from the mailbox it was found in.
+    pub keep_in_inbox: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawMoveTarget {
+    Folder(String),
+    Map {
+        folder: String,
+        #[serde(default)]
+        keep_in_inbox: bool,
+    },
+}
+
+impl From<RawMoveTarget> for MoveTarget {
+    fn from(raw: RawMoveTarget) -> Self {
+        match raw {
+            RawMoveTarget::Folder(folder) => MoveTarget {
+                folder,
+                keep_in_inbox: false,
+            },
+            RawMoveTarget::Map {
+                folder,
+                keep_in_inbox,
+            } => MoveTarget {
+                folder,
+                keep_in_inbox,
+            },
+        }
+    }
+}
+
+impl From<&str> for MoveTarget {
+    fn from(folder: &str) -> Self {
+        MoveTarget {
+            folder: folder.to_string(),
+            keep_in_inbox: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct MessageFilter {
     #[serde(skip_deserializing)]
     pub name: String,
 
+    /// Only consider messages no earlier filter matched; set for the
+    /// config's `default_filter_action`.
+    #[serde(skip)]
+    pub catch_all: bool,
+
+    /// Address patterns for each header. Leaving a field out matches
+    /// anything; an empty list (`to: []`) matches only messages without
+    /// that header.
     #[serde(default, deserialize_with = "deserialize_address_filter")]
     pub to: Option<AddressFilter>,
 
     #[serde(default, deserialize_with = "deserialize_address_filter")]
     pub cc: Option<AddressFilter>,
 
+    /// Headers the `to` patterns apply to; the `cc` matcher is unaffected.
+    #[serde(default)]
+    pub recipient_fields: RecipientFields,
+
+    /// Require exactly one To recipient and no Cc, i.e. mail sent only to you.
+    #[serde(default)]
+    pub sole_recipient: bool,
+
     #[serde(default, deserialize_with = "deserialize_address_filter")]
     pub from: Option<AddressFilter>,
 
-    pub move_to: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_address_filter")]
+    pub reply_to: Option<AddressFilter>,
+
+    #[serde(default, deserialize_with = "deserialize_address_filter")]
+    pub sender: Option<AddressFilter>,
+
+    /// Whether glob patterns (addresses, headers and glob subjects) must match
+    /// the whole value, the default, or may match anywhere inside it.
+    pub anchored: Option<bool>,
+
+    /// Matched against `Delivered-To`/`X-Original-To`, catching mail you
+    /// were Bcc'd on.
+    #[serde(default, deserialize_with = "deserialize_address_filter")]
+    pub delivered_to: Option<AddressFilter>,
+
+    /// Require (or, with `false`, exclude) a missing or blank subject.
+    pub subject_empty: Option<bool>,
+
+    /// `subject` pattern(s), compared as set by `subject_match`.
+    #[serde(flatten)]
+    pub subject: SubjectFilter,
+
+    /// Glob(s) matched against the `List-Id` identifier, e.g. `dev.example.com`.
+    #[serde(default, deserialize_with = "deserialize_address_filter")]
+    pub list_id: Option<AddressFilter>,
+
+    /// Header name (any case) to a glob its value must match, e.g.
+    /// `X-Spam-Flag: YES`. A message without the header doesn't match.
+    #[serde(default, deserialize_with = "deserialize_header_filters")]
+    pub headers: HashMap<String, AddressFilter>,
+
+    /// Match `user+tag@domain` as `user@domain`.
+    #[serde(default)]
+    pub normalize_plus_addressing: bool,
+
+    /// Ignore dots in the local part, Gmail-style (`s.cott@` as `scott@`).
+    #[serde(default)]
+    pub normalize_dots: bool,
+
+    /// Mailbox the filter reads messages from; INBOX when unset.
+    pub mailbox: Option<String>,
+
+    pub move_to: Option<MoveTarget>,
+
+    /// Mailbox a copy of each matched message is APPENDed to, keeping its
+    /// flags and INTERNALDATE.
+    pub append_to: Option<String>,
+
     pub star: Option<bool>,
+
+    /// Act on every INBOX message in a matched message's Gmail thread.
+    #[serde(default)]
+    pub apply_to_thread: bool,
+
+    /// Keep only the newest N matched messages per sender; act on the rest.
+    pub keep_recent_per_sender: Option<usize>,
+
+    /// Skip the filter entirely, as a failure, when it would act on more
+    /// than this many messages; a guard against an overly broad pattern.
+    pub max_affected: Option<usize>,
+
+    /// Bounds (inclusive) on the number of To + Cc recipients.
+    pub min_recipients: Option<usize>,
+    pub max_recipients: Option<usize>,
+
+    /// Bounds (inclusive) on message size, e.g. `500KB` or `5MB`.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub min_size: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_size: Option<u64>,
+
+    /// Require (or, with `false`, exclude) a non-inline attachment.
+    pub has_attachment: Option<bool>,
+
+    /// Require (or, with `false`, exclude) a reply or forward, judged by
+    /// `In-Reply-To`/`References` or a `Re:`/`Fwd:` subject.
+    pub is_reply: Option<bool>,
+
+    /// Require the message to be read (`true`) or unread (`false`).
+    pub seen: Option<bool>,
+
+    /// Bounds on message age, e.g. `3d` or `12h`, measured by `age_basis`.
+    #[serde(default, deserialize_with = "deserialize_age")]
+    pub older_than: Option<TimeDelta>,
+    #[serde(default, deserialize_with = "deserialize_age")]
+    pub newer_than: Option<TimeDelta>,
 }
 
 impl MessageFilter {
+    /// Applies this filter's address normalization to the local part of `email`.
+    pub fn normalize_address(&self, email: &str) -> String {
+        let Some((local, domain)) = email.rsplit_once('@') else {
+            return email.to_string();
+        };
+        let mut local = local.to_string();
+        if self.normalize_plus_addressing {
+            if let Some((user, _tag)) = local.split_once('+') {
+                local = user.to_string();
+            }
+        }
+        if self.normalize_dots {
+            local.retain(|c| c != '.');
+        }
+        format!("{}@{}", local, domain)
+    }
+
+    /// Recompiles the glob patterns to match substrings when `anchored: false`.
+    pub fn apply_anchoring(mut self) -> Result<Self> {
+        if self.anchored.unwrap_or(true) {
+            return Ok(self);
+        }
+        for filter in [
+            &mut self.to,
+            &mut self.cc,
+            &mut self.from,
+            &mut self.reply_to,
+            &mut self.sender,
+            &mut self.delivered_to,
+            &mut self.list_id,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *filter = filter.unanchored()?;
+        }
+        for filter in self.headers.values_mut() {
+            *filter = filter.unanchored()?;
+        }
+        self.subject = std::mem::take(&mut self.subject).unanchored()?;
+        Ok(self)
+    }
+
+    /// The mailbox this filter applies to.
+    pub fn mailbox(&self) -> &str {
+        self.mailbox.as_deref().unwrap_or(DEFAULT_MAILBOX)
+    }
+
     pub fn print_details(&self) {
         println!("\n{}", self.name);
+        if self.catch_all {
+            println!("    catch_all: messages no other filter matched");
+        }
+        if let Some(mailbox) = &self.mailbox {
+            println!("    mailbox: {}", mailbox);
+        }
         if let Some(to) = &self.to {
             println!("    to: {:?}", to.patterns);
         }
         if let Some(cc) = &self.cc {
             println!("    cc: {:?}", cc.patterns);
         }
+        if self.recipient_fields != RecipientFields::To {
+            println!("    recipient_fields: {:?}", self.recipient_fields);
+        }
+        if self.sole_recipient {
+            println!("    sole_recipient: true");
+        }
         if let Some(from) = &self.from {
             println!("    from: {:?}", from.patterns);
         }
-        println!("    move: {}", self.move_to.as_deref().unwrap_or("None"));
+        if let Some(reply_to) = &self.reply_to {
+            println!("    reply_to: {:?}", reply_to.patterns);
+        }
+        if let Some(delivered_to) = &self.delivered_to {
+            println!("    delivered_to: {:?}", delivered_to.patterns);
+        }
+        if let Some(sender) = &self.sender {
+            println!("    sender: {:?}", sender.patterns);
+        }
+        if self.anchored == Some(false) {
+            println!("    anchored: false");
+        }
+        if let Some(subject_empty) = self.subject_empty {
+            println!("    subject_empty: {}", subject_empty);
+        }
+        if self.subject.is_set() {
+            println!(
+                "    subject ({:?}): {:?}",
+                self.subject.mode, self.subject.patterns
+            );
+        }
+        if let Some(list_id) = &self.list_id {
+            println!("    list_id: {:?}", list_id.patterns);
+        }
+        let mut headers: Vec<_> = self.headers.iter().collect();
+        headers.sort_by_key(|(name, _)| *name);
+        for (name, filter) in headers {
+            println!("    header {}: {:?}", name, filter.patterns);
+        }
+        if self.normalize_plus_addressing || self.normalize_dots {
+            println!(
+                "    normalize: plus_addressing={} dots={}",
+                self.normalize_plus_addressing, self.normalize_dots
+            );
+        }
+        match &self.move_to {
+            Some(target) if target.keep_in_inbox => {
+                println!("    move: {} (keep_in_inbox)", target.folder)
+            }
+            Some(target) => println!("    move: {}", target.folder),
+            None => println!("    move: None"),
+        }
+        if let Some(append_to) = &self.append_to {
+            println!("    append: {}", append_to);
+        }
         println!("    star: {}", self.star.unwrap_or(false));
+        if self.apply_to_thread {
+            println!("    apply_to_thread: true");
+        }
+        if let Some(min) = self.min_recipients {
+            println!("    min_recipients: {}", min);
+        }
+        if let Some(max) = self.max_recipients {
+            println!("    max_recipients: {}", max);
+        }
+        if let Some(min) = self.min_size {
+            println!("    min_size: {} bytes", min);
+        }
+        if let Some(max) = self.max_size {
+            println!("    max_size: {} bytes", max);
+        }
+        if let Some(has_attachment) = self.has_attachment {
+            println!("    has_attachment: {}", has_attachment);
+        }
+        if let Some(is_reply) = self.is_reply {
+            println!("    is_reply: {}", is_reply);
+        }
+        if let Some(seen) = self.seen {
+            println!("    seen: {}", seen);
+        }
+        if let Some(older_than) = self.older_than {
+            println!("    older_than: {}", older_than);
+        }
+        if let Some(newer_than) = self.newer_than {
+            println!("    newer_than: {}", newer_than);
+        }
+        if let Some(keep) = self.keep_recent_per_sender {
+            println!("    keep_recent_per_sender: {}", keep);
+        }
+        if let Some(max) = self.max_affected {
+            println!("    max_affected: {}", max);
+        }
     }
 }
 
-fn deserialize_address_filter<'de, D>(deserializer: D) -> Result<Option<AddressFilter>, D::Error>
+/// Restricts `filters` to those named in `names`, keeping configured order.
+/// An empty `names` selects every filter; an unknown name is an error.
+pub fn select_filters(filters: Vec<MessageFilter>, names: &[String]) -> Result<Vec<MessageFilter>> {
+    if names.is_empty() {
+        return Ok(filters);
+    }
+
+    let unknown: Vec<&String> = names
+        .iter()
+        .filter(|name| !filters.iter().any(|filter| &filter.name == *name))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(FilterError::Config(format!(
+            "Unknown filter name(s): {:?}",
+            unknown
+        )));
+    }
+
+    Ok(filters
+        .into_iter()
+        .filter(|filter| names.contains(&filter.name))
+        .collect())
+}
+
+/// Parses a size like `1024`, `500KB`, `5MB` or `1.5GB` into bytes, using
+/// 1024-based units.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        other => {
+            return Err(FilterError::Config(format!(
+                "Unknown size unit '{}' in '{}'",
+                other, size
+            )))
+        }
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| FilterError::Config(format!("Invalid size '{}'", size)))?;
+
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// Parses an age like `90s`, `30m`, `12h`, `3d` or `2w`.
+pub fn parse_age(age: &str) -> Result<TimeDelta> {
+    let age = age.trim();
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    let number: i64 = number
+        .parse()
+        .map_err(|_| FilterError::InvalidDuration(format!("Invalid age '{}'", age)))?;
+
+    let delta = match unit.trim().to_ascii_lowercase().as_str() {
+        "s" => TimeDelta::try_seconds(number),
+        "m" => TimeDelta::try_minutes(number),
+        "h" => TimeDelta::try_hours(number),
+        "d" => TimeDelta::try_days(number),
+        "w" => TimeDelta::try_weeks(number),
+        other => {
+            return Err(FilterError::InvalidDuration(format!(
+                "Unknown age unit '{}' in '{}'; use s, m, h, d or w",
+                other, age
+            )))
+        }
+    };
+    delta.ok_or_else(|| FilterError::InvalidDuration(format!("Age '{}' is out of range", age)))
+}
+
+fn deserialize_age<'de, D>(deserializer: D) -> Result<Option<TimeDelta>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let age = String::deserialize(deserializer)?;
+    parse_age(&age).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_header_filters<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, AddressFilter>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, pattern)| {
+            AddressFilter::new(vec![pattern]).map(|filter| (name.to_ascii_lowercase(), filter))
+        })
+        .collect::<Result<_>>()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct SizeVisitor;
+
+    impl<'de> Visitor<'de> for SizeVisitor {
+        type Value = Option<u64>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a size in bytes or a string like \"5MB\"")
+        }
+
+        fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(Some(value))
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            parse_size(value).map(Some).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(SizeVisitor)
+}
+
+pub(crate) fn deserialize_address_filter<'de, D>(
+    deserializer: D,
+) -> Result<Option<AddressFilter>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -56,9 +499,9 @@ where
         where
             E: serde::de::Error,
         {
-            Ok(Some(AddressFilter {
-                patterns: vec![value.to_string()],
-            }))
+            AddressFilter::new(vec![value.to_string()])
+                .map(Some)
+                .map_err(E::custom)
         }
 
         fn visit_seq<M>(self, mut seq: M) -> Result<Self::Value, M::Error>
@@ -69,9 +512,144 @@ where
             while let Some(email_str) = seq.next_element::<String>()? {
                 patterns.push(email_str);
             }
-            Ok(Some(AddressFilter { patterns }))
+            AddressFilter::new(patterns)
+                .map(Some)
+                .map_err(serde::de::Error::custom)
         }
     }
 
     deserializer.deserialize_any(AddressFilterVisitor)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(names: &[&str]) -> Vec<MessageFilter> {
+        names
+            .iter()
+            .map(|name| MessageFilter {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_select_filters_without_names_keeps_all() {
+        let selected = select_filters(named(&["a", "b", "c"]), &[]).unwrap();
+        assert_eq!(
+            selected.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn test_select_filters_keeps_only_named() {
+        let selected =
+            select_filters(named(&["a", "b", "c"]), &["c".to_string(), "a".to_string()]).unwrap();
+        assert_eq!(
+            selected.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            vec!["a", "c"]
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("500KB").unwrap(), 500 * 1024);
+        assert_eq!(parse_size("5MB").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_size("5 mb").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_size("1.5GB").unwrap(), 1536 * 1024 * 1024);
+        assert!(parse_size("5XB").is_err());
+        assert!(parse_size("MB").is_err());
+    }
+
+    #[test]
+    fn test_size_fields_deserialize() {
+        let filter: MessageFilter =
+            serde_yaml::from_str("min_size: 1MB\nmax_size: 2048\n").unwrap();
+        assert_eq!(filter.min_size, Some(1024 * 1024));
+        assert_eq!(filter.max_size, Some(2048));
+    }
+
+    #[test]
+    fn test_move_to_string_and_map_forms() {
+        let filter: MessageFilter = serde_yaml::from_str("move_to: Archive\n").unwrap();
+        assert_eq!(
+            filter.move_to,
+            Some(MoveTarget {
+                folder: "Archive".to_string(),
+                keep_in_inbox: false
+            })
+        );
+
+        let filter: MessageFilter =
+            serde_yaml::from_str("move_to: { folder: Archive, keep_in_inbox: true }\n").unwrap();
+        assert_eq!(
+            filter.move_to,
+            Some(MoveTarget {
+                folder: "Archive".to_string(),
+                keep_in_inbox: true
+            })
+        );
+
+        let filter: MessageFilter = serde_yaml::from_str("move_to:\n  folder: Archive\n").unwrap();
+        assert_eq!(filter.move_to, Some("Archive".into()));
+
+        assert!(
+            serde_yaml::from_str::<MessageFilter>("move_to: { keep_in_inbox: true }\n").is_err()
+        );
+    }
+
+    #[test]
+    fn test_header_fields_deserialize() {
+        let filter: MessageFilter =
+            serde_yaml::from_str("headers:\n  X-Spam-Flag: 'YES'\n").unwrap();
+        assert_eq!(filter.headers["x-spam-flag"].patterns, vec!["YES"]);
+
+        assert!(serde_yaml::from_str::<MessageFilter>("headers:\n  X-Mailer: '[oops'\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90s").unwrap(), TimeDelta::seconds(90));
+        assert_eq!(parse_age("12h").unwrap(), TimeDelta::hours(12));
+        assert_eq!(parse_age("3d").unwrap(), TimeDelta::days(3));
+        assert_eq!(parse_age("2W").unwrap(), TimeDelta::weeks(2));
+        for age in ["3", "3y", "d", "99999999999999d", "999999999999999w"] {
+            assert!(
+                matches!(parse_age(age), Err(FilterError::InvalidDuration(_))),
+                "{:?}",
+                age
+            );
+        }
+
+        let filter: MessageFilter = serde_yaml::from_str("older_than: 3d\n").unwrap();
+        assert_eq!(filter.older_than, Some(TimeDelta::days(3)));
+    }
+
+    #[test]
+    fn test_subject_fields_deserialize() {
+        let filter: MessageFilter =
+            serde_yaml::from_str("subject: invoice\nsubject_match: contains\nmax_size: 2048\n")
+                .unwrap();
+        assert!(filter.subject.matches("Your Invoice"));
+        assert_eq!(filter.max_size, Some(2048));
+
+        let filter: MessageFilter =
+            serde_yaml::from_str("subject: ['*invoice*', '*receipt*']\n").unwrap();
+        assert!(filter.subject.matches("Receipt for you"));
+
+        assert!(
+            serde_yaml::from_str::<MessageFilter>("subject: '('\nsubject_match: regex\n").is_err()
+        );
+    }
+
+    #[test]
+    fn test_select_filters_rejects_unknown_name() {
+        let err =
+            select_filters(named(&["a"]), &["a".to_string(), "typo".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("typo"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::{FilterError, Result};
+use chrono::NaiveDate;
+
+use crate::labels::quote_label;
+
+pub const DEFAULT_INBOX_QUERY: &str = "ALL";
+
+/// Wraps a Gmail web-style search such as `has:attachment larger:5M` as an
+/// `X-GM-RAW` search key, quoting and escaping it.
+pub fn gmail_raw_query(search: &str) -> String {
+    format!("X-GM-RAW {}", quote_label(search))
+}
+
+/// Matches replies to, and later messages in the thread of, the message
+/// with `message_id` (e.g. `<abc@example.com>`).
+pub fn references_query(message_id: &str) -> String {
+    let quoted = quote_label(message_id.trim());
+    format!(
+        "OR HEADER REFERENCES {} HEADER IN-REPLY-TO {}",
+        quoted, quoted
+    )
+}
+
+/// A `SINCE` search key; IMAP compares whole dates, inclusively.
+pub fn since_query(date: NaiveDate) -> String {
+    format!("SINCE {}", date.format("%d-%b-%Y"))
+}
+
+/// Combines `inbox_query` with an optional Gmail search, thread root and
+/// `SINCE` date; SEARCH keys given side by side must all match.
+pub fn build_search_query(
+    inbox_query: &str,
+    gmail_search: Option<&str>,
+    references: Option<&str>,
+    since: Option<NaiveDate>,
+) -> String {
+    let extra: Vec<String> = gmail_search
+        .map(gmail_raw_query)
+        .into_iter()
+        .chain(references.map(references_query))
+        .chain(since.map(since_query))
+        .collect();
+    match (inbox_query, extra.is_empty()) {
+        (_, true) => inbox_query.to_string(),
+        (DEFAULT_INBOX_QUERY, false) => extra.join(" "),
+        _ => format!("{} {}", inbox_query, extra.join(" ")),
+    }
+}
+
+/// Checks that `query` is safe to splice into a SEARCH command: non-empty,
+/// a single line, with balanced parentheses and closed quoted strings.
+/// It does not check that the search keys themselves exist.
+pub fn validate_imap_query(query: &str) -> Result<()> {
+    if query.trim().is_empty() {
+        return Err(FilterError::InvalidQuery(
+            "IMAP search query is empty".to_string(),
+        ));
+    }
+    if query.contains(['\r', '\n']) {
+        return Err(FilterError::InvalidQuery(format!(
+            "IMAP search query must be a single line: {:?}",
+            query
+        )));
+    }
+
+    let mut depth = 0usize;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for c in query.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => {
+                depth = depth.checked_sub(1).ok_or_else(|| {
+                    FilterError::InvalidQuery(format!(
+                        "Unbalanced ')' in IMAP search query: {}",
+                        query
+                    ))
+                })?;
+            }
+            _ => {}
+        }
+    }
+
+    if in_quotes {
+        return Err(FilterError::InvalidQuery(format!(
+            "Unterminated quoted string in IMAP search query: {}",
+            query
+        )));
+    }
+    if depth != 0 {
+        return Err(FilterError::InvalidQuery(format!(
+            "Unbalanced '(' in IMAP search query: {}",
+            query
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_imap_query_accepts_valid_queries() {
+        for query in [
+            "ALL",
+            "UNSEEN",
+            "OR (FROM \"a\") (SUBJECT \"b (c\")",
+            "SUBJECT \"say \\\"hi\\\"\"",
+        ] {
+            assert!(validate_imap_query(query).is_ok(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_gmail_search_is_quoted_and_escaped() {
+        assert_eq!(
+            gmail_raw_query("has:attachment larger:5M"),
+            "X-GM-RAW \"has:attachment larger:5M\""
+        );
+        let query = gmail_raw_query("subject:\"weekly report\" from:a\\b");
+        assert_eq!(
+            query,
+            "X-GM-RAW \"subject:\\\"weekly report\\\" from:a\\\\b\""
+        );
+        assert!(validate_imap_query(&query).is_ok());
+    }
+
+    #[test]
+    fn test_build_search_query() {
+        let since = NaiveDate::from_ymd_opt(2025, 3, 7);
+        assert_eq!(build_search_query("ALL", None, None, None), "ALL");
+        assert_eq!(
+            build_search_query("ALL", Some("is:unread"), None, None),
+            "X-GM-RAW \"is:unread\""
+        );
+        assert_eq!(
+            build_search_query("UNSEEN", Some("larger:5M"), None, None),
+            "UNSEEN X-GM-RAW \"larger:5M\""
+        );
+        assert_eq!(
+            build_search_query("ALL", None, None, since),
+            "SINCE 07-Mar-2025"
+        );
+        assert_eq!(
+            build_search_query("UNSEEN", Some("is:unread"), None, since),
+            "UNSEEN X-GM-RAW \"is:unread\" SINCE 07-Mar-2025"
+        );
+        assert_eq!(
+            build_search_query("UNSEEN", None, Some("<root@example.com>"), since),
+            "UNSEEN OR HEADER REFERENCES \"<root@example.com>\" HEADER IN-REPLY-TO \"<root@example.com>\" SINCE 07-Mar-2025"
+        );
+    }
+
+    #[test]
+    fn test_references_query() {
+        let query = references_query(" <root@example.com> ");
+        assert_eq!(
+            query,
+            "OR HEADER REFERENCES \"<root@example.com>\" HEADER IN-REPLY-TO \"<root@example.com>\""
+        );
+        assert!(validate_imap_query(&query).is_ok());
+    }
+
+    #[test]
+    fn test_validate_imap_query_rejects_malformed_queries() {
+        for query in [
+            "",
+            "  ",
+            "UNSEEN\r\nA1 LOGOUT",
+            "(UNSEEN",
+            "UNSEEN)",
+            "SUBJECT \"open",
+        ] {
+            assert!(
+                matches!(
+                    validate_imap_query(query),
+                    Err(FilterError::InvalidQuery(_))
+                ),
+                "{:?}",
+                query
+            );
+        }
+    }
+}
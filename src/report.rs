@@ -0,0 +1,133 @@
+use crate::error::{FilterError, Result};
+use serde::Serialize;
+use std::io::Write;
+
+use crate::message::Message;
+
+/// One action taken (or attempted) on one message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ActionRecord {
+    pub uid: u32,
+    pub seq: u32,
+    pub subject: String,
+    pub from: String,
+    /// `move:<folder>` or `star`.
+    pub action: String,
+    /// `ok`, `failed` or `skipped`.
+    pub result: String,
+}
+
+impl ActionRecord {
+    pub fn new(msg: &Message, action: impl Into<String>, result: &str) -> Self {
+        Self {
+            uid: msg.uid,
+            seq: msg.seq,
+            subject: msg.subject.clone(),
+            from: msg
+                .from
+                .first()
+                .map(|(_, email)| email.clone())
+                .unwrap_or_default(),
+            action: action.into(),
+            result: result.to_string(),
+        }
+    }
+}
+
+/// Formats `--report` can be written in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+const CSV_HEADER: [&str; 6] = ["uid", "seq", "subject", "from", "action", "result"];
+
+/// Quotes a CSV field when it holds a comma, quote or line break, doubling
+/// embedded quotes (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn write_report<W: Write>(
+    records: &[ActionRecord],
+    format: OutputFormat,
+    mut out: W,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, records)
+                .map_err(|e| FilterError::Io(format!("Failed to write JSON report: {}", e)))?;
+            writeln!(out)?;
+        }
+        OutputFormat::Csv => {
+            writeln!(out, "{}", CSV_HEADER.join(","))?;
+            for record in records {
+                let fields = [
+                    record.uid.to_string(),
+                    record.seq.to_string(),
+                    csv_field(&record.subject),
+                    csv_field(&record.from),
+                    csv_field(&record.action),
+                    csv_field(&record.result),
+                ];
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<ActionRecord> {
+        vec![
+            ActionRecord {
+                uid: 41,
+                seq: 3,
+                subject: "Invoice, \"final\" notice".to_string(),
+                from: "billing@example.com".to_string(),
+                action: "move:Archive/2024".to_string(),
+                result: "ok".to_string(),
+            },
+            ActionRecord {
+                uid: 42,
+                seq: 4,
+                subject: "plain".to_string(),
+                from: "a@example.com".to_string(),
+                action: "star".to_string(),
+                result: "failed".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_csv_report_escapes_fields() {
+        let mut out = Vec::new();
+        write_report(&records(), OutputFormat::Csv, &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "uid,seq,subject,from,action,result\n\
+             41,3,\"Invoice, \"\"final\"\" notice\",billing@example.com,move:Archive/2024,ok\n\
+             42,4,plain,a@example.com,star,failed\n"
+        );
+    }
+
+    #[test]
+    fn test_json_report() {
+        let mut out = Vec::new();
+        write_report(&records(), OutputFormat::Json, &mut out).unwrap();
+
+        let parsed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(parsed[0]["subject"], "Invoice, \"final\" notice");
+        assert_eq!(parsed[1]["result"], "failed");
+    }
+}
@@ -0,0 +1,218 @@
+use crate::address_filter::unanchor;
+use crate::error::{FilterError, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+use serde::Deserialize;
+
+/// How a filter's `subject` patterns are compared with the subject.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubjectMatch {
+    /// Shell-style globs: `*invoice*`.
+    #[default]
+    Glob,
+    /// Plain substring: `invoice`.
+    Contains,
+    /// The whole subject.
+    Exact,
+    /// Regular expressions, searched anywhere in the subject.
+    Regex,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Matcher {
+    #[default]
+    Any,
+    Glob(GlobSet),
+    Contains(Vec<String>),
+    Exact(Vec<String>),
+    Regex(RegexSet),
+}
+
+/// A filter's `subject` condition. Glob, contains and exact ignore case;
+/// regexes are case-sensitive unless they opt out with `(?i)`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(try_from = "RawSubjectFilter")]
+pub struct SubjectFilter {
+    pub patterns: Vec<String>,
+    pub mode: SubjectMatch,
+    matcher: Matcher,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct RawSubjectFilter {
+    subject: Option<OneOrMany>,
+    #[serde(default)]
+    subject_match: SubjectMatch,
+}
+
+impl TryFrom<RawSubjectFilter> for SubjectFilter {
+    type Error = FilterError;
+
+    fn try_from(raw: RawSubjectFilter) -> Result<Self> {
+        let patterns = match raw.subject {
+            None => Vec::new(),
+            Some(OneOrMany::One(pattern)) => vec![pattern],
+            Some(OneOrMany::Many(patterns)) => patterns,
+        };
+        SubjectFilter::new(raw.subject_match, patterns)
+    }
+}
+
+impl SubjectFilter {
+    /// Compiles `patterns` under `mode`; no patterns means no condition.
+    pub fn new(mode: SubjectMatch, patterns: Vec<String>) -> Result<Self> {
+        let matcher = if patterns.is_empty() {
+            Matcher::Any
+        } else {
+            match mode {
+                SubjectMatch::Glob => {
+                    let mut builder = GlobSetBuilder::new();
+                    for pattern in &patterns {
+                        let glob = GlobBuilder::new(pattern)
+                            .case_insensitive(true)
+                            .build()
+                            .map_err(|e| {
+                                FilterError::Config(format!(
+                                    "Invalid subject pattern '{}': {}",
+                                    pattern, e
+                                ))
+                            })?;
+                        builder.add(glob);
+                    }
+                    Matcher::Glob(builder.build().map_err(|e| {
+                        FilterError::Config(format!(
+                            "Invalid subject patterns {:?}: {}",
+                            patterns, e
+                        ))
+                    })?)
+                }
+                SubjectMatch::Contains => Matcher::Contains(
+                    patterns
+                        .iter()
+                        .map(|pattern| pattern.to_lowercase())
+                        .collect(),
+                ),
+                SubjectMatch::Exact => Matcher::Exact(
+                    patterns
+                        .iter()
+                        .map(|pattern| pattern.to_lowercase())
+                        .collect(),
+                ),
+                SubjectMatch::Regex => Matcher::Regex(RegexSet::new(&patterns).map_err(|e| {
+                    FilterError::Config(format!("Invalid subject regex {:?}: {}", patterns, e))
+                })?),
+            }
+        };
+        Ok(Self {
+            patterns,
+            mode,
+            matcher,
+        })
+    }
+
+    /// Glob patterns also match anywhere inside the subject, as if wrapped in
+    /// `*...*`; the other modes are unchanged.
+    pub fn unanchored(self) -> Result<Self> {
+        if self.mode != SubjectMatch::Glob {
+            return Ok(self);
+        }
+        let matcher = SubjectFilter::new(
+            self.mode,
+            self.patterns
+                .iter()
+                .map(|pattern| unanchor(pattern))
+                .collect(),
+        )?
+        .matcher;
+        Ok(Self { matcher, ..self })
+    }
+
+    pub fn is_set(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    /// True when any pattern matches `subject`, or there are no patterns.
+    pub fn matches(&self, subject: &str) -> bool {
+        match &self.matcher {
+            Matcher::Any => true,
+            Matcher::Glob(globs) => globs.is_match(subject),
+            Matcher::Contains(needles) => {
+                let subject = subject.to_lowercase();
+                needles
+                    .iter()
+                    .any(|needle| subject.contains(needle.as_str()))
+            }
+            Matcher::Exact(subjects) => subjects.contains(&subject.to_lowercase()),
+            Matcher::Regex(regexes) => regexes.is_match(subject),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBJECT: &str = "Your Invoice #1234 from Acme";
+
+    fn filter(mode: SubjectMatch, pattern: &str) -> SubjectFilter {
+        SubjectFilter::new(mode, vec![pattern.to_string()]).unwrap()
+    }
+
+    #[test]
+    fn test_glob_mode() {
+        assert!(filter(SubjectMatch::Glob, "*invoice*").matches(SUBJECT));
+        assert!(
+            !filter(SubjectMatch::Glob, "invoice").matches(SUBJECT),
+            "a bare glob is a whole-subject match"
+        );
+    }
+
+    #[test]
+    fn test_unanchored_glob() {
+        assert!(!filter(SubjectMatch::Glob, "invoice").matches(SUBJECT));
+        assert!(filter(SubjectMatch::Glob, "invoice")
+            .unanchored()
+            .unwrap()
+            .matches(SUBJECT));
+        assert!(
+            !filter(SubjectMatch::Exact, "invoice")
+                .unanchored()
+                .unwrap()
+                .matches(SUBJECT),
+            "only globs are unanchored"
+        );
+    }
+
+    #[test]
+    fn test_contains_mode() {
+        assert!(filter(SubjectMatch::Contains, "invoice").matches(SUBJECT));
+        assert!(!filter(SubjectMatch::Contains, "receipt").matches(SUBJECT));
+    }
+
+    #[test]
+    fn test_exact_mode() {
+        assert!(filter(SubjectMatch::Exact, "your invoice #1234 from acme").matches(SUBJECT));
+        assert!(!filter(SubjectMatch::Exact, "invoice").matches(SUBJECT));
+    }
+
+    #[test]
+    fn test_regex_mode() {
+        assert!(filter(SubjectMatch::Regex, r"Invoice #\d+").matches(SUBJECT));
+        assert!(!filter(SubjectMatch::Regex, r"^Invoice").matches(SUBJECT));
+        assert!(SubjectFilter::new(SubjectMatch::Regex, vec!["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_no_patterns_matches_anything() {
+        assert!(SubjectFilter::default().matches(SUBJECT));
+        assert!(!SubjectFilter::default().is_set());
+    }
+}